@@ -0,0 +1,104 @@
+use std::fmt;
+use std::io;
+use tokio::net::UnixStream;
+
+use crate::config;
+
+/// Identity of a connected client, taken from the kernel via
+/// `SO_PEERCRED` rather than from anything the client sends.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+    pub user: String,
+    /// Root or a member of the configured admin group.
+    pub admin: bool,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    PeerCred(io::Error),
+    UnknownUid(u32),
+    UserMismatch { claimed: String, actual: String },
+}
+
+impl Credentials {
+    pub fn from_stream(
+        stream: &UnixStream,
+    ) -> Result<Self, AuthError> {
+        let cred =
+            stream.peer_cred().map_err(AuthError::PeerCred)?;
+        let user = users::get_user_by_uid(cred.uid())
+            .and_then(|u| u.name().to_str().map(String::from))
+            .ok_or(AuthError::UnknownUid(cred.uid()))?;
+
+        let admin_group = config::current().admin_group.clone();
+        let admin = cred.uid() == 0
+            || users::get_user_groups(&user, cred.gid())
+                .unwrap_or_default()
+                .iter()
+                .any(|group| {
+                    group.name() == admin_group.as_str()
+                });
+
+        Ok(Self {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+            user,
+            admin,
+        })
+    }
+
+    /// Check the username a client claims in its messages against
+    /// the one its socket actually belongs to.
+    pub fn verify(
+        &self,
+        claimed: &str,
+    ) -> Result<(), AuthError> {
+        if claimed == self.user {
+            Ok(())
+        } else {
+            Err(AuthError::UserMismatch {
+                claimed: claimed.into(),
+                actual: self.user.clone(),
+            })
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::PeerCred(e) => {
+                write!(
+                    f,
+                    "Couldn't read peer credentials: {}",
+                    e
+                )
+            }
+            AuthError::UnknownUid(uid) => {
+                write!(f, "No user with uid {}", uid)
+            }
+            AuthError::UserMismatch { claimed, actual } => {
+                write!(
+                    f,
+                    "Claimed to be user {} but connected as {}",
+                    claimed, actual
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {
+    fn source(
+        &self,
+    ) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuthError::PeerCred(e) => Some(e),
+            _ => None,
+        }
+    }
+}
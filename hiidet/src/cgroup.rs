@@ -0,0 +1,238 @@
+use hiisi_common::protocol::{ResourceLimits, ResourceUsage};
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, write};
+use std::ffi::{CStr, CString};
+use std::os::fd::BorrowedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::config;
+
+/// Controllers that limits are set through.
+const CONTROLLERS: [&str; 4] = ["memory", "cpu", "pids", "io"];
+
+/// Period `cpu.max` quotas are measured against, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Whether managed processes get a cgroup each. Needs the unified
+/// (v2) hierarchy and permission to create our slice in it, and is
+/// decided once per daemon run.
+pub fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        let config = config::current();
+        if !config.cgroups.enabled {
+            tracing::info!(
+                "cgroups disabled, tracking process trees through \
+                 /proc"
+            );
+            return false;
+        }
+        let enabled =
+            Path::new("/sys/fs/cgroup/cgroup.controllers")
+                .exists()
+                && std::fs::create_dir_all(&config.cgroups.root)
+                    .is_ok();
+        if enabled {
+            // What our slice gets is up to whoever manages the
+            // hierarchy above it, usually systemd's `Delegate=`
+            let available = std::fs::read_to_string(
+                config.cgroups.root.join("cgroup.controllers"),
+            )
+            .unwrap_or_default();
+            let missing: Vec<&str> = CONTROLLERS
+                .into_iter()
+                .filter(|c| {
+                    !available.split_whitespace().any(|a| a == *c)
+                })
+                .collect();
+            if !missing.is_empty() {
+                tracing::warn!(
+                    "cgroup controllers {} aren't delegated to {}, \
+                     so limits needing them are unsupported",
+                    missing.join(", "),
+                    config.cgroups.root.display()
+                );
+            }
+            delegate(&config.cgroups.root);
+        } else {
+            tracing::info!(
+                "cgroup v2 unavailable, tracking process trees \
+                 through /proc"
+            );
+        }
+        enabled
+    })
+}
+
+/// Make whichever of our controllers are available to `cgroup`
+/// available to its children as well.
+fn delegate(cgroup: &Path) {
+    for controller in CONTROLLERS {
+        // Not every kernel has every controller, which only
+        // matters once a limit actually needs it
+        std::fs::write(
+            cgroup.join("cgroup.subtree_control"),
+            format!("+{}", controller),
+        )
+        .ok();
+    }
+}
+
+/// Create (or reuse) the cgroup for process `id` of user `uid`.
+/// `user_memory` caps the memory of all of the user's processes
+/// together.
+pub fn create(
+    uid: u32,
+    id: u32,
+    user_memory: Option<u64>,
+) -> std::io::Result<PathBuf> {
+    let slice = config::current()
+        .cgroups
+        .root
+        .join(format!("user-{}.slice", uid));
+    std::fs::create_dir_all(&slice)?;
+    delegate(&slice);
+
+    // Written every time, so that quota changes apply from the
+    // user's next start
+    let max =
+        user_memory.map_or("max".into(), |m| m.to_string());
+    let written = std::fs::write(slice.join("memory.max"), max);
+    // Lifting a quota is allowed to fail without memory control
+    if let Some(e) =
+        written.err().filter(|_| user_memory.is_some())
+    {
+        tracing::warn!(
+            "Couldn't apply memory quota of uid {}: {}",
+            uid,
+            e
+        );
+    }
+
+    let path = slice.join(format!("process-{}.scope", id));
+    std::fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// Write `limits` into a freshly created cgroup. Fails if the
+/// controller a limit needs isn't available.
+pub fn set_limits(
+    cgroup: &Path,
+    limits: &ResourceLimits,
+) -> std::io::Result<()> {
+    let settings = [
+        ("memory.max", limits.memory_max.map(|m| m.to_string())),
+        ("cpu.weight", limits.cpu_weight.map(|w| w.to_string())),
+        (
+            "cpu.max",
+            limits.cpu_quota.map(|quota| {
+                format!(
+                    "{} {}",
+                    u64::from(quota) * CPU_PERIOD / 100,
+                    CPU_PERIOD
+                )
+            }),
+        ),
+        ("pids.max", limits.pids_max.map(|p| p.to_string())),
+        (
+            "io.weight",
+            limits.io_weight.map(|w| format!("default {}", w)),
+        ),
+    ];
+
+    for (file, value) in settings {
+        let Some(value) = value else { continue };
+        let path = cgroup.join(file);
+        // The file only exists if its controller is enabled here
+        if !path.exists() {
+            let controller =
+                file.split('.').next().unwrap_or(file);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "The {} cgroup controller isn't available",
+                    controller
+                ),
+            ));
+        }
+        std::fs::write(path, value).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Couldn't set {}: {}", file, e),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Usage accounted to the cgroup so far.
+pub fn usage(cgroup: &Path) -> Option<ResourceUsage> {
+    let read = |file: &str| {
+        std::fs::read_to_string(cgroup.join(file)).ok()
+    };
+    // Flat keyed files like `cpu.stat` and `memory.events`
+    let field = |file: &str, key: &str| {
+        read(file)?.lines().find_map(|line| {
+            let (k, v) = line.split_once(' ')?;
+            (k == key).then(|| v.trim().parse().ok()).flatten()
+        })
+    };
+
+    Some(ResourceUsage {
+        memory: read("memory.current")
+            .and_then(|m| m.trim().parse().ok()),
+        cpu_time: Duration::from_micros(field(
+            "cpu.stat",
+            "usage_usec",
+        )?),
+        oom_kills: field("memory.events", "oom_kill")
+            .unwrap_or_default(),
+    })
+}
+
+/// Path of the cgroup's `cgroup.procs` as a C string, prepared
+/// ahead of time for `join`.
+pub fn procs_path(cgroup: &Path) -> std::io::Result<CString> {
+    CString::new(
+        cgroup.join("cgroup.procs").as_os_str().as_bytes(),
+    )
+    .map_err(std::io::Error::other)
+}
+
+/// Move the calling process into a cgroup. Meant to run between
+/// fork and exec, so it must not allocate.
+pub fn join(procs: &CStr) -> nix::Result<()> {
+    let fd = open(procs, OFlag::O_WRONLY, Mode::empty())?;
+    // Writing 0 moves the writer itself
+    let result =
+        write(unsafe { BorrowedFd::borrow_raw(fd) }, b"0")
+            .map(|_| ());
+    close(fd)?;
+    result
+}
+
+pub fn procs(cgroup: &Path) -> Vec<u32> {
+    std::fs::read_to_string(cgroup.join("cgroup.procs"))
+        .map(|procs| {
+            procs
+                .lines()
+                .filter_map(|l| l.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// SIGKILL everything in the cgroup at once, including anything
+/// forked while we'd otherwise be iterating. Needs Linux 5.14.
+pub fn kill(cgroup: &Path) -> std::io::Result<()> {
+    std::fs::write(cgroup.join("cgroup.kill"), "1")
+}
+
+/// Remove the cgroup once it's empty.
+pub fn remove(cgroup: &Path) {
+    std::fs::remove_dir(cgroup).ok();
+}
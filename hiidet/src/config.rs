@@ -0,0 +1,606 @@
+use hiisi_common::protocol::{deserialize_size, StopPolicy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+
+pub const DEFAULT_PATH: &str = "/etc/hiisi/hiidet.toml";
+
+/// Settings that are only read on startup, so a reload can't
+/// change them.
+const RESTART_ONLY: [&str; 5] = [
+    "socket",
+    "state_dir",
+    "metrics",
+    "cgroups.enabled",
+    "cgroups.root",
+];
+
+/// Daemon settings from `/etc/hiisi/hiidet.toml`. Every field has
+/// a default, so a missing file means a stock setup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where clients connect.
+    pub socket: PathBuf,
+    /// Holds `processes.ron` and `ports.ron`.
+    pub state_dir: PathBuf,
+    /// Where process logs go, with `{user}` and `{home}` standing
+    /// in for the owner's name and home directory.
+    pub log_root: String,
+    /// Members may manage other users' processes and ports, as
+    /// root can.
+    pub admin_group: String,
+    /// Serve Prometheus metrics on this address.
+    pub metrics: Option<SocketAddr>,
+    pub ports: PortsConfig,
+    pub stop: StopConfig,
+    pub intervals: Intervals,
+    pub cgroups: CgroupConfig,
+    pub quotas: Quotas,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortsConfig {
+    pub min: u16,
+    pub max: u16,
+    /// Mark ports nothing has listened on for this long as stale.
+    #[serde(with = "humantime_serde")]
+    pub stale_after: Duration,
+    /// Free stale ports after this much longer.
+    #[serde(with = "humantime_serde")]
+    pub grace: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StopConfig {
+    /// Stop signal and timeouts for processes that don't set
+    /// their own.
+    pub signal: String,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(with = "humantime_serde")]
+    pub term_timeout: Duration,
+    /// How long to wait for a process to disappear after SIGKILL.
+    #[serde(with = "humantime_serde")]
+    pub kill_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Intervals {
+    /// How often CPU usage is sampled. Also how often re-adopted
+    /// processes are checked on, on kernels without pidfds.
+    #[serde(with = "humantime_serde")]
+    pub monitor: Duration,
+    /// How often port leases are renewed and reclaimed.
+    #[serde(with = "humantime_serde")]
+    pub port_gc: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CgroupConfig {
+    /// Give each process a cgroup when cgroup v2 is available.
+    pub enabled: bool,
+    pub root: PathBuf,
+}
+
+/// Per-user limits. A user's own entry wins, then the most generous
+/// of their groups', then `default`, separately for each limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quotas {
+    pub default: QuotaLimits,
+    pub users: BTreeMap<String, QuotaLimits>,
+    pub groups: BTreeMap<String, QuotaLimits>,
+}
+
+/// Limits on what one user may hold at once. Unset means no limit.
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize,
+)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaLimits {
+    /// Processes running or due to restart. Exited ones left in
+    /// the process table don't count.
+    pub processes: Option<u64>,
+    pub ports: Option<u64>,
+    /// Memory used by all of the user's processes together.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub memory: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socket: "/run/hiisi/hiisi.sock".into(),
+            state_dir: "/etc/hiisi".into(),
+            log_root: "{home}/.logs".into(),
+            admin_group: "hiisi-admin".into(),
+            metrics: None,
+            ports: PortsConfig::default(),
+            stop: StopConfig::default(),
+            intervals: Intervals::default(),
+            cgroups: CgroupConfig::default(),
+            quotas: Quotas::default(),
+        }
+    }
+}
+
+impl Default for PortsConfig {
+    fn default() -> Self {
+        Self {
+            min: 1024,
+            max: 65535,
+            stale_after: Duration::from_secs(7 * 24 * 3600),
+            grace: Duration::from_secs(3 * 24 * 3600),
+        }
+    }
+}
+
+impl Default for StopConfig {
+    fn default() -> Self {
+        Self {
+            signal: "SIGINT".into(),
+            timeout: Duration::from_secs(15),
+            term_timeout: Duration::from_secs(15),
+            kill_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl StopConfig {
+    /// These settings, overridden by whatever `policy` sets.
+    pub fn with(&self, policy: &StopPolicy) -> Self {
+        Self {
+            signal: policy
+                .signal
+                .clone()
+                .unwrap_or_else(|| self.signal.clone()),
+            timeout: policy.timeout.unwrap_or(self.timeout),
+            term_timeout: policy
+                .term_timeout
+                .unwrap_or(self.term_timeout),
+            kill_timeout: self.kill_timeout,
+        }
+    }
+}
+
+impl Default for Intervals {
+    fn default() -> Self {
+        Self {
+            monitor: Duration::from_secs(1),
+            port_gc: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            root: "/sys/fs/cgroup/hiisi.slice".into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl Config {
+    /// Load and validate the config at `path`. The default path
+    /// may be missing, in which case the defaults apply; one given
+    /// explicitly must exist.
+    pub fn load(
+        path: &Path,
+        explicit: bool,
+    ) -> Result<Self, ConfigError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    && !explicit =>
+            {
+                return Ok(Self::default());
+            }
+            Err(e) => {
+                return Err(ConfigError::Io(path.into(), e));
+            }
+        };
+
+        let config: Self = toml::from_str(&contents)
+            .map_err(|e| ConfigError::Parse(path.into(), e))?;
+        config.validate().map_err(|msg| {
+            ConfigError::Invalid(path.into(), msg)
+        })?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let paths = [
+            ("socket", &self.socket),
+            ("state_dir", &self.state_dir),
+            ("cgroups.root", &self.cgroups.root),
+        ];
+        for (name, path) in paths {
+            if !path.is_absolute() {
+                return Err(format!(
+                    "{} must be an absolute path",
+                    name
+                ));
+            }
+        }
+        if !self.log_root.starts_with('/')
+            && !self.log_root.starts_with("{home}")
+        {
+            return Err(
+                "log_root must be an absolute path or start with {home}"
+                    .into(),
+            );
+        }
+        // Each user's logs are handed over to them, so they can't
+        // share a directory
+        if !self.log_root.contains("{user}")
+            && !self.log_root.contains("{home}")
+        {
+            return Err(
+                "log_root must contain {user} or {home}".into(),
+            );
+        }
+
+        if self.ports.min == 0 || self.ports.min > self.ports.max
+        {
+            return Err(format!(
+                "Port range {}-{} is empty or includes 0",
+                self.ports.min, self.ports.max
+            ));
+        }
+
+        crate::process::parse_signal(&self.stop.signal)
+            .map_err(|e| format!("stop.signal: {}", e))?;
+
+        let durations = [
+            ("ports.stale_after", self.ports.stale_after),
+            ("stop.kill_timeout", self.stop.kill_timeout),
+            ("intervals.monitor", self.intervals.monitor),
+            ("intervals.port_gc", self.intervals.port_gc),
+        ];
+        for (name, duration) in durations {
+            if duration.is_zero() {
+                return Err(format!(
+                    "{} must be above zero",
+                    name
+                ));
+            }
+        }
+
+        // Reloads compare settings as TOML, whose integers stop
+        // short of the largest sizes
+        self.flatten().map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    pub fn processes_path(&self) -> PathBuf {
+        self.state_dir.join("processes.ron")
+    }
+
+    pub fn ports_path(&self) -> PathBuf {
+        self.state_dir.join("ports.ron")
+    }
+
+    /// Settings that differ from `other`, as dotted keys with their
+    /// old and new values. A missing value shows as `none`.
+    fn changes(
+        &self,
+        other: &Config,
+    ) -> Result<Vec<(String, String, String)>, toml::ser::Error>
+    {
+        let (old, new) = (self.flatten()?, other.flatten()?);
+        let none = || "none".to_string();
+        let mut keys: Vec<&String> =
+            old.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys
+            .into_iter()
+            .filter(|key| old.get(*key) != new.get(*key))
+            .map(|key| {
+                (
+                    key.clone(),
+                    old.get(key)
+                        .map_or_else(none, |v| v.to_string()),
+                    new.get(key)
+                        .map_or_else(none, |v| v.to_string()),
+                )
+            })
+            .collect())
+    }
+
+    fn flatten(
+        &self,
+    ) -> Result<BTreeMap<String, toml::Value>, toml::ser::Error>
+    {
+        fn walk(
+            prefix: &str,
+            table: toml::Table,
+            out: &mut BTreeMap<String, toml::Value>,
+        ) {
+            for (key, value) in table {
+                let key = match prefix.is_empty() {
+                    true => key,
+                    false => format!("{}.{}", prefix, key),
+                };
+                match value {
+                    toml::Value::Table(table) => {
+                        walk(&key, table, out)
+                    }
+                    value => {
+                        out.insert(key, value);
+                    }
+                }
+            }
+        }
+
+        let mut out = BTreeMap::new();
+        walk("", toml::Table::try_from(self)?, &mut out);
+        Ok(out)
+    }
+
+    /// The log directory of `user`, whose home is `home`.
+    pub fn log_root_for(
+        &self,
+        user: &str,
+        home: &Path,
+    ) -> PathBuf {
+        self.log_root
+            .replace("{home}", &home.to_string_lossy())
+            .replace("{user}", user)
+            .into()
+    }
+}
+
+static CURRENT: LazyLock<RwLock<Arc<Config>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(Config::default())));
+
+/// The config in effect.
+pub fn current() -> Arc<Config> {
+    CURRENT.read().unwrap().clone()
+}
+
+/// Replace the config in effect. Takes effect for whatever reads
+/// it next; nothing already running is restarted.
+pub fn set(config: Config) {
+    *CURRENT.write().unwrap() = Arc::new(config);
+}
+
+/// Re-read the config at `path` and put it in effect, logging what
+/// changed. Settings only read on startup keep their old values. A
+/// config that fails to load changes nothing.
+pub fn reload(
+    path: &Path,
+    explicit: bool,
+) -> Result<(), ConfigError> {
+    let mut new = Config::load(path, explicit)?;
+    let old = current();
+
+    let changes = old.changes(&new).map_err(|e| {
+        ConfigError::Invalid(path.into(), e.to_string())
+    })?;
+    if changes.is_empty() {
+        tracing::info!(
+            "Reloaded {}, nothing changed",
+            path.display()
+        );
+        return Ok(());
+    }
+    for (key, from, to) in changes {
+        match RESTART_ONLY.contains(&key.as_str()) {
+            true => tracing::warn!(
+                "{} changed from {} to {}, restart hiidet to apply",
+                key,
+                from,
+                to
+            ),
+            false => {
+                tracing::info!(
+                    "{} changed from {} to {}",
+                    key,
+                    from,
+                    to
+                )
+            }
+        }
+    }
+
+    new.socket = old.socket.clone();
+    new.state_dir = old.state_dir.clone();
+    new.metrics = old.metrics;
+    new.cgroups = old.cgroups.clone();
+    set(new);
+    Ok(())
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => {
+                write!(
+                    f,
+                    "Couldn't read {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+            ConfigError::Parse(path, e) => {
+                write!(
+                    f,
+                    "Invalid config {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+            ConfigError::Invalid(path, msg) => {
+                write!(
+                    f,
+                    "Invalid config {}: {}",
+                    path.display(),
+                    msg
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(
+        &self,
+    ) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            ConfigError::Invalid(..) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_sizes_beyond_toml() {
+        let config: Config = toml::from_str(
+            "[quotas.default]\nmemory = \"10000000T\"",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        let mut old = Config::default();
+        old.quotas.default.memory = Some(u64::MAX);
+        assert!(old.changes(&Config::default()).is_err());
+    }
+
+    fn invalid(toml: &str) -> String {
+        let config: Config = toml::from_str(toml).unwrap();
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn accepts_defaults() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(invalid("socket = \"hiisi.sock\"")
+            .contains("socket"));
+        assert!(
+            invalid("log_root = \"logs\"").contains("log_root")
+        );
+        assert!(invalid("log_root = \"/var/log/hiisi\"")
+            .contains("{user}"));
+        assert!(
+            invalid("[ports]\nmin = 0").contains("Port range")
+        );
+        assert!(invalid("[ports]\nmin = 3000\nmax = 2000")
+            .contains("Port range"));
+        assert!(invalid("[intervals]\nmonitor = \"0s\"")
+            .contains("intervals.monitor"));
+        assert!(invalid("[stop]\nsignal = \"SIGNOPE\"")
+            .contains("stop.signal"));
+    }
+
+    #[test]
+    fn fills_stop_policies_from_defaults() {
+        let config: Config = toml::from_str(
+            "[stop]\nsignal = \"QUIT\"\ntimeout = \"1m\"",
+        )
+        .unwrap();
+        let policy = StopPolicy {
+            timeout: Some(Duration::from_secs(5)),
+            ..StopPolicy::default()
+        };
+        let stop = config.stop.with(&policy);
+        assert_eq!(stop.signal, "QUIT");
+        assert_eq!(stop.timeout, Duration::from_secs(5));
+        assert_eq!(stop.term_timeout, Duration::from_secs(15));
+        assert_eq!(stop.kill_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn lists_changed_settings() {
+        let new: Config = toml::from_str(
+            "admin_group = \"wheel\"\n[quotas.users.alice]\nports = 3",
+        )
+        .unwrap();
+
+        let changes = Config::default().changes(&new).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    "admin_group".into(),
+                    "\"hiisi-admin\"".into(),
+                    "\"wheel\"".into()
+                ),
+                (
+                    "quotas.users.alice.ports".into(),
+                    "none".into(),
+                    "3".into()
+                ),
+            ]
+        );
+        assert!(Config::default()
+            .changes(&Config::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn reload_keeps_restart_only_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hiidet.toml");
+        std::fs::write(
+            &path,
+            "socket = \"/run/elsewhere.sock\"\n\
+             [cgroups]\nenabled = false\n\
+             [ports]\nmin = 2000",
+        )
+        .unwrap();
+
+        reload(&path, true).unwrap();
+        let config = current();
+        assert_eq!(config.ports.min, 2000);
+        assert_eq!(config.socket, Config::default().socket);
+        assert!(config.cgroups.enabled);
+    }
+
+    #[test]
+    fn reload_keeps_config_that_fails_to_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hiidet.toml");
+        std::fs::write(&path, "[ports]\nmin = 0").unwrap();
+
+        assert!(matches!(
+            reload(&path, true),
+            Err(ConfigError::Invalid(..))
+        ));
+        assert!(matches!(
+            reload(&dir.path().join("missing.toml"), true),
+            Err(ConfigError::Io(..))
+        ));
+        assert_ne!(current().ports.min, 0);
+    }
+}
@@ -1,16 +1,62 @@
+mod auth;
+mod cgroup;
+mod config;
+mod metrics;
 mod monitor;
+mod persist;
 mod ports;
 mod process;
+mod quota;
+mod reaper;
 mod server;
+mod sockets;
 mod state;
+mod tree;
 
+use clap::Parser;
+use config::Config;
 use server::Server;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tracing::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Configuration file
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Check the configuration file and exit
+    #[arg(long)]
+    check_config: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let config_path = args
+        .config
+        .clone()
+        .unwrap_or_else(|| config::DEFAULT_PATH.into());
+    let config = match Config::load(
+        &config_path,
+        args.config.is_some(),
+    ) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.check_config {
+        println!("{} is valid", config_path.display());
+        return Ok(());
+    }
+    config::set(config);
+    let config = config::current();
+
     // Initialize logging
     tracing_subscriber::fmt()
         .with_target(false)
@@ -26,12 +72,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("Starting hiidet daemon");
 
     // Create socket directory if it doesn't exist
-    std::fs::create_dir_all("/run/hiisi")?;
+    if let Some(dir) = config.socket.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
 
-    let socket_path =
-        Arc::new(PathBuf::from("/run/hiisi/hiisi.sock"));
+    let socket_path = Arc::new(config.socket.clone());
     let socket_path_cleanup = Arc::clone(&socket_path);
-    let server = Server::new();
+    let server = match Server::new() {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            error!(
+                "Couldn't load saved state, fix or remove the file \
+                 to start: {}",
+                e
+            );
+            std::process::exit(1);
+        }
+    };
 
     // Handle SIGTERM gracefully
     let (tx, rx) = tokio::sync::oneshot::channel();
@@ -44,8 +101,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     })?;
 
+    // Reload the config on SIGHUP
+    let mut hangup = signal(SignalKind::hangup())?;
+    let explicit = args.config.is_some();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Reloading {}", config_path.display());
+            if let Err(e) =
+                config::reload(&config_path, explicit)
+            {
+                error!("{}; keeping the current config", e);
+            }
+        }
+    });
+
     // Run server in background task
     let server_handle = tokio::spawn({
+        let server = Arc::clone(&server);
         let socket_path = Arc::clone(&socket_path);
         async move {
             if let Err(e) = server.run(&socket_path).await {
@@ -54,12 +126,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
+    let metrics_handle = config.metrics.map(|addr| {
+        // Metrics name every user's processes
+        if !addr.ip().is_loopback() {
+            warn!(
+                "Serving metrics on non-loopback address {}",
+                addr
+            );
+        }
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(e) = server.run_metrics(addr).await {
+                error!("Metrics server error: {}", e);
+            }
+        })
+    });
+
     // Wait for shutdown signal
     rx.await?;
     info!("Shutdown signal received");
 
     // Clean up
     server_handle.abort();
+    if let Some(handle) = metrics_handle {
+        handle.abort();
+    }
     if socket_path_cleanup.exists() {
         std::fs::remove_file(&*socket_path_cleanup)?;
     }
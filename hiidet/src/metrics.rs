@@ -0,0 +1,448 @@
+use hiisi_common::protocol::ProcessStatus;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::monitor::SystemMonitor;
+use crate::ports::PortState;
+use crate::state::State;
+
+/// Largest request head we bother reading.
+const MAX_REQUEST: usize = 8192;
+/// How long a client gets to send its request, so that idle
+/// connections don't pile up.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve `GET /metrics` in the Prometheus text format. Just enough
+/// HTTP for a scraper: one request per connection, no keep-alive.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<Mutex<State>>,
+    ports: Arc<Mutex<PortState>>,
+    monitor: Arc<Mutex<SystemMonitor>>,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        let ports = ports.clone();
+        let monitor = monitor.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle(stream, &state, &ports, &monitor).await
+            {
+                tracing::debug!(
+                    "Metrics request from {} failed: {}",
+                    peer,
+                    e
+                );
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    state: &Mutex<State>,
+    ports: &Mutex<PortState>,
+    monitor: &Mutex<SystemMonitor>,
+) -> std::io::Result<()> {
+    let head = tokio::time::timeout(
+        READ_TIMEOUT,
+        read_head(&mut stream),
+    )
+    .await;
+    let (status, body) = match head {
+        Ok(Ok(Some(request))) if request.len() > MAX_REQUEST => {
+            (
+                "431 Request Header Fields Too Large",
+                "Request too large\n".into(),
+            )
+        }
+        Ok(Ok(Some(request))) => {
+            route(&request, state, ports, monitor).await
+        }
+        // Hung up before finishing the request
+        Ok(Ok(None)) => return Ok(()),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => ("408 Request Timeout", "Timed out\n".into()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The status and body answering `request`.
+async fn route(
+    request: &[u8],
+    state: &Mutex<State>,
+    ports: &Mutex<PortState>,
+    monitor: &Mutex<SystemMonitor>,
+) -> (&'static str, String) {
+    let request = String::from_utf8_lossy(request);
+    let mut line =
+        request.lines().next().unwrap_or_default().split(' ');
+    match (line.next(), line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", render(state, ports, monitor).await)
+        }
+        (Some("GET"), _) => {
+            ("404 Not Found", "Not found\n".into())
+        }
+        _ => ("405 Method Not Allowed", "Use GET\n".into()),
+    }
+}
+
+/// Read a request up to the end of its head, or past
+/// `MAX_REQUEST` bytes, at which point reading stops. None if the
+/// client hung up first.
+async fn read_head(
+    stream: &mut TcpStream,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            break;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(request))
+}
+
+/// Quote a label value.
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n");
+    format!("\"{}\"", escaped)
+}
+
+/// Writes one metric family at a time, with its HELP and TYPE.
+struct Family<'a> {
+    out: &'a mut String,
+}
+
+impl Family<'_> {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).ok();
+        writeln!(self.out, "# TYPE {} {}", name, kind).ok();
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: f64) {
+        match labels.is_empty() {
+            true => writeln!(self.out, "{} {}", name, value),
+            false => writeln!(
+                self.out,
+                "{}{{{}}} {}",
+                name, labels, value
+            ),
+        }
+        .ok();
+    }
+}
+
+#[derive(Default)]
+struct UserTotals {
+    processes: u64,
+    running: u64,
+    cpu_usage: f64,
+    rss: u64,
+    ports: u64,
+    active_ports: u64,
+}
+
+async fn render(
+    state: &Mutex<State>,
+    ports: &Mutex<PortState>,
+    monitor: &Mutex<SystemMonitor>,
+) -> String {
+    // Same lock order as status requests
+    let (system, processes) = {
+        let state = state.lock().await;
+        let mut monitor = monitor.lock().await;
+        let system = monitor.update();
+        (system, state.list_processes(&monitor))
+    };
+    // Looking up listeners scans every process's descriptors, so
+    // it's done on a copy rather than while holding up requests
+    let ports = ports.lock().await.clone();
+    let allocations = ports.lookup(None);
+
+    let mut out = String::new();
+    let mut f = Family { out: &mut out };
+
+    f.header(
+        "hiisi_system_cpu_usage_percent",
+        "gauge",
+        "CPU usage of the whole machine",
+    );
+    f.sample(
+        "hiisi_system_cpu_usage_percent",
+        "",
+        system.total_cpu.into(),
+    );
+    f.header(
+        "hiisi_system_memory_total_bytes",
+        "gauge",
+        "Total memory of the machine",
+    );
+    f.sample(
+        "hiisi_system_memory_total_bytes",
+        "",
+        system.total_memory as f64,
+    );
+    f.header(
+        "hiisi_system_memory_used_bytes",
+        "gauge",
+        "Memory in use on the machine",
+    );
+    f.sample(
+        "hiisi_system_memory_used_bytes",
+        "",
+        system.used_memory as f64,
+    );
+
+    let labels: Vec<String> = processes
+        .iter()
+        .map(|p| {
+            format!(
+                "id=\"{}\",name={},user={}",
+                p.id,
+                label(p.name.as_deref().unwrap_or_default()),
+                label(&p.user)
+            )
+        })
+        .collect();
+
+    type Getter =
+        fn(&hiisi_common::protocol::ProcessInfo) -> Option<f64>;
+    let per_process: [(&str, &str, &str, Getter); 9] = [
+        (
+            "hiisi_process_up",
+            "gauge",
+            "Whether the process is running",
+            |p| {
+                Some(matches!(p.status, ProcessStatus::Running)
+                    as u8 as f64)
+            },
+        ),
+        (
+            "hiisi_process_gave_up",
+            "gauge",
+            "Whether hiisi stopped restarting the process",
+            |p| {
+                Some(matches!(p.status, ProcessStatus::GaveUp)
+                    as u8 as f64)
+            },
+        ),
+        (
+            "hiisi_process_restarts_total",
+            "counter",
+            "Times the process has been restarted",
+            |p| Some(p.restarts.into()),
+        ),
+        (
+            "hiisi_process_last_exit_code",
+            "gauge",
+            "Exit code of the last run, 128 + n for signal n",
+            |p| p.last_exit_code.map(f64::from),
+        ),
+        (
+            "hiisi_process_cpu_usage_percent",
+            "gauge",
+            "CPU usage of the process tree, in percent of one CPU",
+            |p| Some(p.cpu_usage.into()),
+        ),
+        (
+            "hiisi_process_resident_memory_bytes",
+            "gauge",
+            "Resident memory of the process tree",
+            |p| Some(p.rss as f64),
+        ),
+        (
+            "hiisi_process_threads",
+            "gauge",
+            "Threads in the process tree",
+            |p| Some(p.threads as f64),
+        ),
+        (
+            "hiisi_process_open_fds",
+            "gauge",
+            "Open file descriptors in the process tree",
+            |p| Some(p.open_fds as f64),
+        ),
+        (
+            "hiisi_process_oom_kills_total",
+            "counter",
+            "OOM kills within the process's cgroup",
+            |p| p.usage.as_ref().map(|u| u.oom_kills as f64),
+        ),
+    ];
+
+    for (name, kind, help, get) in per_process {
+        f.header(name, kind, help);
+        for (process, labels) in processes.iter().zip(&labels) {
+            if let Some(value) = get(process) {
+                f.sample(name, labels, value);
+            }
+        }
+    }
+
+    let mut users: BTreeMap<&str, UserTotals> = BTreeMap::new();
+    for process in &processes {
+        let totals = users.entry(&process.user).or_default();
+        totals.processes += 1;
+        if matches!(process.status, ProcessStatus::Running) {
+            totals.running += 1;
+        }
+        totals.cpu_usage += f64::from(process.cpu_usage);
+        totals.rss += process.rss;
+    }
+    for port in &allocations {
+        let totals = users.entry(&port.user).or_default();
+        totals.ports += 1;
+        totals.active_ports += u64::from(port.active);
+    }
+
+    type UserGetter = fn(&UserTotals) -> f64;
+    let per_user: [(&str, &str, UserGetter); 6] = [
+        (
+            "hiisi_user_processes",
+            "Processes managed for the user",
+            |u| u.processes as f64,
+        ),
+        (
+            "hiisi_user_running_processes",
+            "Running processes managed for the user",
+            |u| u.running as f64,
+        ),
+        (
+            "hiisi_user_cpu_usage_percent",
+            "CPU usage of the user's processes, in percent of one CPU",
+            |u| u.cpu_usage,
+        ),
+        (
+            "hiisi_user_resident_memory_bytes",
+            "Resident memory of the user's processes",
+            |u| u.rss as f64,
+        ),
+        (
+            "hiisi_user_ports",
+            "Ports allocated to the user",
+            |u| u.ports as f64,
+        ),
+        (
+            "hiisi_user_active_ports",
+            "Ports allocated to the user that something listens on",
+            |u| u.active_ports as f64,
+        ),
+    ];
+
+    for (name, help, get) in per_user {
+        f.header(name, "gauge", help);
+        for (user, totals) in &users {
+            f.sample(
+                name,
+                &format!("user={}", label(user)),
+                get(totals),
+            );
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::process;
+    use hiisi_common::protocol::{Backoff, RestartPolicy};
+
+    /// The status line `serve` answers `request` with.
+    async fn status_line(request: &[u8]) -> String {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let monitor = Arc::new(Mutex::new(SystemMonitor::new()));
+        tokio::spawn(serve(
+            listener,
+            Arc::default(),
+            Arc::default(),
+            monitor,
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().into()
+    }
+
+    #[tokio::test]
+    async fn escapes_labels() {
+        let mut process =
+            process(RestartPolicy::Never, Backoff::default());
+        process.spec.name = Some("a\"b\\c\nd".into());
+        let mut state = State::default();
+        state.processes.insert(1, process);
+
+        let out = render(
+            &Mutex::new(state),
+            &Mutex::default(),
+            &Mutex::new(SystemMonitor::new()),
+        )
+        .await;
+        assert!(out.contains(r#"name="a\"b\\c\nd""#));
+        assert!(out
+            .lines()
+            .all(|line| !line.starts_with("d\"")));
+    }
+
+    #[tokio::test]
+    async fn answers_oversized_requests() {
+        let request = vec![b'a'; MAX_REQUEST + 100];
+        assert_eq!(
+            status_line(&request).await,
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_slow_requests() {
+        assert_eq!(
+            status_line(b"GET /metrics HTTP/1.1\r\n").await,
+            "HTTP/1.1 408 Request Timeout"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        assert_eq!(
+            status_line(b"GET /metrics HTTP/1.1\r\n\r\n").await,
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(
+            status_line(b"POST /metrics HTTP/1.1\r\n\r\n").await,
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+    }
+}
@@ -1,23 +1,26 @@
-use std::collections::HashMap;
-use sysinfo::System;
+use sysinfo::{ProcessesToUpdate, System};
 
 pub struct SystemMonitor {
     sys: System,
     last_update: std::time::Instant,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ProcessStats {
+    /// Percent of one CPU, so above 100 for multithreaded work.
     pub cpu_usage: f32,
-    pub memory_kb: u64,
+    /// Resident set size in bytes.
+    pub memory: u64,
+    pub threads: u64,
+    pub open_fds: u64,
 }
 
+/// Machine-wide figures. Memory is in bytes.
 #[derive(Debug)]
 pub struct SystemStats {
     pub total_cpu: f32,
-    pub total_memory_kb: u64,
-    pub used_memory_kb: u64,
-    pub process_stats: HashMap<u32, ProcessStats>,
+    pub total_memory: u64,
+    pub used_memory: u64,
 }
 
 impl SystemMonitor {
@@ -28,38 +31,26 @@ impl SystemMonitor {
         }
     }
 
-    pub fn update(&mut self) -> SystemStats {
+    /// Refresh CPU, memory and process figures. CPU usage is
+    /// measured between refreshes, so this should run regularly.
+    pub fn refresh(&mut self) {
         // Only refresh if more than 1s passed
         if self.last_update.elapsed().as_secs() >= 1 {
-            self.sys.refresh_all();
+            self.sys.refresh_cpu_usage();
+            self.sys.refresh_memory();
+            self.sys
+                .refresh_processes(ProcessesToUpdate::All, true);
             self.last_update = std::time::Instant::now();
         }
+    }
 
-        let total_cpu = self.sys.global_cpu_usage();
-
-        let total_memory_kb = self.sys.total_memory();
-        let used_memory_kb = self.sys.used_memory();
-
-        let process_stats = self
-            .sys
-            .processes()
-            .iter()
-            .map(|(pid, process)| {
-                (
-                    pid.as_u32(),
-                    ProcessStats {
-                        cpu_usage: process.cpu_usage(),
-                        memory_kb: process.memory(),
-                    },
-                )
-            })
-            .collect();
+    pub fn update(&mut self) -> SystemStats {
+        self.refresh();
 
         SystemStats {
-            total_cpu,
-            total_memory_kb,
-            used_memory_kb,
-            process_stats,
+            total_cpu: self.sys.global_cpu_usage(),
+            total_memory: self.sys.total_memory(),
+            used_memory: self.sys.used_memory(),
         }
     }
 
@@ -70,10 +61,44 @@ impl SystemMonitor {
         self.sys.process(sysinfo::Pid::from_u32(pid)).map(
             |process| ProcessStats {
                 cpu_usage: process.cpu_usage(),
-                memory_kb: process.memory(),
+                memory: process.memory(),
+                threads: count_threads(pid),
+                open_fds: count_fds(pid),
             },
         )
     }
+
+    /// Stats summed over every pid in a process tree.
+    pub fn tree_stats(&self, pids: &[u32]) -> ProcessStats {
+        pids.iter()
+            .filter_map(|&pid| self.get_process_stats(pid))
+            .fold(ProcessStats::default(), |total, stats| {
+                ProcessStats {
+                    cpu_usage: total.cpu_usage + stats.cpu_usage,
+                    memory: total.memory + stats.memory,
+                    threads: total.threads + stats.threads,
+                    open_fds: total.open_fds + stats.open_fds,
+                }
+            })
+    }
+}
+
+fn count_threads(pid: u32) -> u64 {
+    std::fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Threads:"))
+                .and_then(|n| n.trim().parse().ok())
+        })
+        .unwrap_or_default()
+}
+
+fn count_fds(pid: u32) -> u64 {
+    std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .map(|fds| fds.count() as u64)
+        .unwrap_or_default()
 }
 
 impl Default for SystemMonitor {
@@ -0,0 +1,247 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum PersistError {
+    Io(PathBuf, io::Error),
+    Serialize(ron::Error),
+    /// A file that exists but can't be parsed.
+    Corrupt(PathBuf, ron::error::SpannedError),
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+/// Where the previous good copy of `path` is kept.
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling(path, ".bak")
+}
+
+/// Atomically replace `path` with `value`, keeping the old copy as
+/// a backup. The file is written privately (mode 0600) to a
+/// temporary sibling, synced, and renamed over `path`, so a crash
+/// leaves either the old or the new file in place, never half of
+/// one.
+pub fn save<T: Serialize>(
+    path: &Path,
+    value: &T,
+) -> Result<(), PersistError> {
+    let contents = ron::to_string(value)
+        .map_err(PersistError::Serialize)?;
+    let io_err = |e| PersistError::Io(path.into(), e);
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    std::fs::create_dir_all(dir).map_err(io_err)?;
+
+    let tmp = sibling(path, ".tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .map_err(io_err)?;
+    file.write_all(contents.as_bytes()).map_err(io_err)?;
+    file.sync_all().map_err(io_err)?;
+
+    if path.exists() {
+        let backup = backup_path(path);
+        match std::fs::remove_file(&backup) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(io_err(e));
+            }
+            _ => (),
+        }
+        std::fs::hard_link(path, &backup).map_err(io_err)?;
+    }
+    std::fs::rename(&tmp, path).map_err(io_err)?;
+
+    // Make the rename itself durable
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(io_err)
+}
+
+/// Load `path`, falling back to its backup if it's missing or
+/// can't be parsed. Returns `None` if neither exists, which is the
+/// case on first start.
+pub fn load<T: DeserializeOwned>(
+    path: &Path,
+) -> Result<Option<T>, PersistError> {
+    let backup = backup_path(path);
+    let mut error = None;
+
+    for candidate in [path, &backup] {
+        let contents = match std::fs::read_to_string(candidate) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                continue;
+            }
+            Err(e) => {
+                return Err(PersistError::Io(
+                    candidate.into(),
+                    e,
+                ));
+            }
+        };
+
+        match ron::from_str(&contents) {
+            Ok(value) => {
+                if let Some(e) = &error {
+                    tracing::warn!(
+                        "{}; recovered from {}",
+                        e,
+                        candidate.display()
+                    );
+                    // Move the broken file aside, both for a look
+                    // later and so the next save doesn't back it up
+                    // over the good copy
+                    std::fs::rename(
+                        path,
+                        sibling(path, ".corrupt"),
+                    )
+                    .map_err(|e| {
+                        PersistError::Io(path.into(), e)
+                    })?;
+                }
+                return Ok(Some(value));
+            }
+            Err(e) => {
+                let e =
+                    PersistError::Corrupt(candidate.into(), e);
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::Io(path, e) => {
+                write!(
+                    f,
+                    "IO error on {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+            PersistError::Serialize(e) => {
+                write!(f, "Couldn't serialize: {}", e)
+            }
+            PersistError::Corrupt(path, e) => {
+                write!(
+                    f,
+                    "Couldn't parse {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PersistError {
+    fn source(
+        &self,
+    ) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistError::Io(_, e) => Some(e),
+            PersistError::Serialize(e) => Some(e),
+            PersistError::Corrupt(_, e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/values.ron");
+
+        assert_eq!(load::<Vec<u32>>(&path).unwrap(), None);
+        save(&path, &vec![1, 2]).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(vec![1, 2]));
+        assert_eq!(
+            path.metadata().unwrap().mode() & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn keeps_previous_copy_as_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("values.ron");
+
+        save(&path, &vec![1]).unwrap();
+        save(&path, &vec![2]).unwrap();
+        let backup = std::fs::read_to_string(backup_path(&path));
+        assert_eq!(backup.unwrap(), "[1]");
+    }
+
+    #[test]
+    fn recovers_corrupt_file_from_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("values.ron");
+        save(&path, &vec![1]).unwrap();
+        save(&path, &vec![2]).unwrap();
+        std::fs::write(&path, "[2, oops").unwrap();
+
+        assert_eq!(load(&path).unwrap(), Some(vec![1]));
+        // Moved aside, so the next save can't back it up over the
+        // good copy
+        assert!(!path.exists());
+        let corrupt = sibling(&path, ".corrupt");
+        assert_eq!(
+            std::fs::read_to_string(corrupt).unwrap(),
+            "[2, oops"
+        );
+
+        save(&path, &vec![3]).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn falls_back_to_backup_when_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("values.ron");
+        std::fs::write(backup_path(&path), "[4]").unwrap();
+
+        assert_eq!(load(&path).unwrap(), Some(vec![4]));
+    }
+
+    #[test]
+    fn refuses_when_both_are_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("values.ron");
+        std::fs::write(&path, "nope").unwrap();
+        std::fs::write(backup_path(&path), "nope either")
+            .unwrap();
+
+        match load::<Vec<u32>>(&path) {
+            Err(PersistError::Corrupt(file, _)) => {
+                assert_eq!(file, path)
+            }
+            other => {
+                panic!("expected corruption, got {:?}", other)
+            }
+        }
+        // Nothing moved, so it can be looked at as it is
+        assert!(path.exists());
+    }
+}
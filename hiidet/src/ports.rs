@@ -1,47 +1,64 @@
-use hiisi_common::protocol::PortInfo;
+use hiisi_common::protocol::{
+    ErrorKind, PortInfo, RequestError,
+};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
-const MIN_PORT: u16 = 1024;
-const MAX_PORT: u16 = 65535;
-const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+use crate::config;
+use crate::persist::{self, PersistError};
+use crate::sockets;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PortAllocation {
     pub user: String,
     pub allocated_at: SystemTime,
+    /// Last time something was seen listening on the port.
+    #[serde(default)]
+    pub last_seen: Option<SystemTime>,
+    /// Never reclaimed, however long it sits idle.
+    #[serde(default)]
+    pub pinned: bool,
+    /// A user the owner offered the port to.
+    #[serde(default)]
+    pub offered_to: Option<String>,
 }
 
-#[derive(Default, Serialize, Deserialize)]
+impl PortAllocation {
+    /// When the lease was last renewed, by allocation or use.
+    fn renewed_at(&self) -> SystemTime {
+        self.last_seen.unwrap_or(self.allocated_at)
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct PortState {
     allocations: HashMap<u16, PortAllocation>,
-    #[serde(skip)]
-    last_save: Option<SystemTime>,
 }
 
 impl PortState {
-    pub fn load() -> Self {
-        std::fs::read_to_string("/etc/hiisi/ports.ron")
-            .map(|contents| ron::from_str(&contents).unwrap())
-            .unwrap_or_default()
+    /// Load the saved allocations. Fails rather than start empty
+    /// if they're unreadable, which would hand out ports that are
+    /// in use.
+    pub fn load() -> Result<Self, PersistError> {
+        Ok(persist::load(&config::current().ports_path())?
+            .unwrap_or_default())
     }
 
+    /// Called after every change, so that a crash loses nothing.
     fn save(&self) {
-        let contents = ron::to_string(self).unwrap();
-        std::fs::write("/etc/hiisi/ports.ron", contents).ok();
-    }
-
-    pub fn check_save(&mut self) {
-        let now = SystemTime::now();
-        if self
-            .last_save
-            .and_then(|t| now.duration_since(t).ok())
-            .map_or(true, |d| d >= SAVE_INTERVAL)
+        // Tests keep their allocations to themselves
+        if cfg!(test) {
+            return;
+        }
+        if let Err(e) =
+            persist::save(&config::current().ports_path(), self)
         {
-            self.save();
-            self.last_save = Some(now);
+            tracing::error!(
+                "Couldn't save port allocations: {}",
+                e
+            );
         }
     }
 
@@ -66,14 +83,22 @@ impl PortState {
             PortAllocation {
                 user,
                 allocated_at: SystemTime::now(),
+                last_seen: None,
+                pinned: false,
+                offered_to: None,
             },
         );
+        self.save();
     }
 
     fn allocate_random(&mut self, user: String) -> Option<u16> {
+        let range = {
+            let config = config::current();
+            config.ports.min..=config.ports.max
+        };
         let mut rng = rand::thread_rng();
         for _ in 0..100 {
-            let port = rng.gen_range(MIN_PORT..=MAX_PORT);
+            let port = rng.gen_range(range.clone());
             if self.is_available(port) {
                 self.allocate_specific(user, port);
                 return Some(port);
@@ -82,28 +107,429 @@ impl PortState {
         None
     }
 
-    pub fn free(&mut self, port: u16) -> bool {
-        self.allocations.remove(&port).is_some()
+    /// How many ports `user` holds.
+    pub fn count(&self, user: &str) -> u64 {
+        self.allocations
+            .values()
+            .filter(|a| a.user == user)
+            .count() as u64
+    }
+
+    pub fn is_owned_by(&self, port: u16, user: &str) -> bool {
+        self.allocations
+            .get(&port)
+            .is_some_and(|a| a.user == user)
+    }
+
+    /// The allocation of `port`, if `user` may manage it.
+    fn managed(
+        &mut self,
+        port: u16,
+        user: &str,
+        admin: bool,
+    ) -> Result<&mut PortAllocation, RequestError> {
+        match self.allocations.get_mut(&port) {
+            Some(alloc) if alloc.user == user || admin => {
+                Ok(alloc)
+            }
+            Some(alloc) => Err(RequestError::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Port {} belongs to {}",
+                    port, alloc.user
+                ),
+            )),
+            None => Err(RequestError::new(
+                ErrorKind::NotFound,
+                format!("Port {} is not allocated", port),
+            )),
+        }
+    }
+
+    /// Free a port held by `user`, or anyone's if `admin`.
+    pub fn free(
+        &mut self,
+        port: u16,
+        user: &str,
+        admin: bool,
+    ) -> Result<(), RequestError> {
+        self.managed(port, user, admin)?;
+        self.allocations.remove(&port);
+        self.save();
+        Ok(())
+    }
+
+    /// Offer `port` to `to`, who takes it over by accepting it.
+    /// Admins may offer anyone's port.
+    pub fn transfer(
+        &mut self,
+        port: u16,
+        user: &str,
+        admin: bool,
+        to: String,
+    ) -> Result<(), RequestError> {
+        let alloc = self.managed(port, user, admin)?;
+        if alloc.user == to {
+            return Err(RequestError::new(
+                ErrorKind::InvalidRequest,
+                format!(
+                    "Port {} already belongs to {}",
+                    port, to
+                ),
+            ));
+        }
+
+        alloc.offered_to = Some(to);
+        self.save();
+        Ok(())
+    }
+
+    /// Take over a port that was offered to `user`.
+    pub fn accept(
+        &mut self,
+        port: u16,
+        user: &str,
+    ) -> Result<(), RequestError> {
+        match self.allocations.get_mut(&port) {
+            Some(alloc)
+                if alloc.offered_to.as_deref() == Some(user) =>
+            {
+                alloc.user = user.into();
+                alloc.offered_to = None;
+                self.save();
+                Ok(())
+            }
+            _ => Err(RequestError::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Port {} hasn't been offered to you",
+                    port
+                ),
+            )),
+        }
+    }
+
+    /// Pin or unpin one of `user`'s ports. Returns false if they
+    /// don't hold it.
+    pub fn set_pinned(
+        &mut self,
+        port: u16,
+        user: &str,
+        pinned: bool,
+    ) -> bool {
+        match self.allocations.get_mut(&port) {
+            Some(alloc) if alloc.user == user => {
+                alloc.pinned = pinned;
+                self.save();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// When an idle allocation will be reclaimed, if it's stale.
+    fn reclaim_at(
+        &self,
+        alloc: &PortAllocation,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        let lease = &config::current().ports;
+        let stale_at = alloc.renewed_at() + lease.stale_after;
+        (!alloc.pinned && now >= stale_at)
+            .then(|| stale_at + lease.grace)
+    }
+
+    /// Renew the lease of every port something listens on and
+    /// free those that have been idle past their grace period.
+    /// Returns the freed ports along with their owners.
+    pub fn collect_garbage(&mut self) -> Vec<(u16, String)> {
+        let ports: HashSet<u16> =
+            self.allocations.keys().copied().collect();
+        let listening =
+            sockets::scan(&ports).into_keys().collect();
+        let reclaimed = self
+            .collect_garbage_at(SystemTime::now(), &listening);
+        self.save();
+        reclaimed
+    }
+
+    /// `collect_garbage` as of `now`, with `listening` being the
+    /// ports something listens on.
+    fn collect_garbage_at(
+        &mut self,
+        now: SystemTime,
+        listening: &HashSet<u16>,
+    ) -> Vec<(u16, String)> {
+        for (port, alloc) in &mut self.allocations {
+            if listening.contains(port) {
+                alloc.last_seen = Some(now);
+            }
+        }
+
+        let expired: Vec<u16> = self
+            .allocations
+            .iter()
+            .filter(|(_, alloc)| {
+                self.reclaim_at(alloc, now)
+                    .is_some_and(|t| now >= t)
+            })
+            .map(|(&port, _)| port)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|port| {
+                let alloc = self.allocations.remove(&port)?;
+                Some((port, alloc.user))
+            })
+            .collect()
     }
 
     fn is_available(&self, port: u16) -> bool {
-        port >= MIN_PORT
-            && port <= MAX_PORT
+        let config = config::current();
+        (config.ports.min..=config.ports.max).contains(&port)
             && !self.allocations.contains_key(&port)
     }
 
     pub fn lookup(&self, user: Option<String>) -> Vec<PortInfo> {
-        self.allocations
+        let allocations: Vec<_> = self
+            .allocations
             .iter()
+            // Include ports offered to the user so they can see them
             .filter(|(_, alloc)| {
-                user.as_ref().map_or(true, |u| alloc.user == *u)
+                user.as_ref().is_none_or(|u| {
+                    alloc.user == *u
+                        || alloc.offered_to.as_ref() == Some(u)
+                })
             })
-            .map(|(&port, alloc)| PortInfo {
-                port,
-                user: alloc.user.clone(),
-                active: false, // TODO: implement port activity check
-                allocated_at: alloc.allocated_at.into(),
+            .collect();
+        let ports: HashSet<u16> =
+            allocations.iter().map(|(port, _)| **port).collect();
+        let sockets = sockets::scan(&ports);
+        let now = SystemTime::now();
+
+        allocations
+            .into_iter()
+            .map(|(&port, alloc)| {
+                let active = sockets.contains_key(&port);
+                PortInfo {
+                    port,
+                    user: alloc.user.clone(),
+                    active,
+                    pid: sockets.get(&port).and_then(|s| s.pid),
+                    process: None,
+                    last_seen: match active {
+                        true => Some(now.into()),
+                        false => alloc.last_seen.map(Into::into),
+                    },
+                    pinned: alloc.pinned,
+                    offered_to: alloc.offered_to.clone(),
+                    // Listening now renews the lease at the next
+                    // collection
+                    reclaim_at: (!active)
+                        .then(|| self.reclaim_at(alloc, now))
+                        .flatten()
+                        .map(Into::into),
+                    allocated_at: alloc.allocated_at.into(),
+                }
             })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn allocation(
+        user: &str,
+        allocated_at: SystemTime,
+    ) -> PortAllocation {
+        PortAllocation {
+            user: user.into(),
+            allocated_at,
+            last_seen: None,
+            pinned: false,
+            offered_to: None,
+        }
+    }
+
+    fn held_by(user: &str, port: u16) -> PortState {
+        let mut state = PortState::default();
+        state
+            .allocations
+            .insert(port, allocation(user, SystemTime::now()));
+        state
+    }
+
+    fn kind(result: Result<(), RequestError>) -> ErrorKind {
+        result.unwrap_err().kind
+    }
+
+    #[test]
+    fn only_owners_and_admins_free_ports() {
+        let mut state = held_by("alice", 3000);
+        assert_eq!(
+            kind(state.free(3000, "bob", false)),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            kind(state.free(3001, "alice", false)),
+            ErrorKind::NotFound
+        );
+        assert!(state.free(3000, "bob", true).is_ok());
+        assert_eq!(state.count("alice"), 0);
+
+        let mut state = held_by("alice", 3000);
+        assert!(state.free(3000, "alice", false).is_ok());
+        assert!(state.allocations.is_empty());
+    }
+
+    #[test]
+    fn transfers_wait_for_acceptance() {
+        let mut state = held_by("alice", 3000);
+        assert_eq!(
+            kind(state.transfer(
+                3000,
+                "bob",
+                false,
+                "bob".into()
+            )),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            kind(state.transfer(
+                3000,
+                "alice",
+                false,
+                "alice".into()
+            )),
+            ErrorKind::InvalidRequest
+        );
+
+        // Offered, but still alice's until bob accepts
+        state
+            .transfer(3000, "alice", false, "bob".into())
+            .unwrap();
+        assert!(state.is_owned_by(3000, "alice"));
+        assert_eq!(
+            kind(state.free(3000, "bob", false)),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            kind(state.accept(3000, "carol")),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            kind(state.accept(3001, "bob")),
+            ErrorKind::NotFound
+        );
+
+        state.accept(3000, "bob").unwrap();
+        assert!(state.is_owned_by(3000, "bob"));
+        assert_eq!(state.allocations[&3000].offered_to, None);
+        assert_eq!(
+            kind(state.free(3000, "alice", false)),
+            ErrorKind::PermissionDenied
+        );
+        // Taken up, so it can't be accepted twice
+        assert_eq!(
+            kind(state.accept(3000, "bob")),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn admins_offer_ports_without_taking_them() {
+        let mut state = held_by("alice", 3000);
+        state
+            .transfer(3000, "root", true, "bob".into())
+            .unwrap();
+        assert!(state.is_owned_by(3000, "alice"));
+        assert_eq!(
+            kind(state.accept(3000, "root")),
+            ErrorKind::NotFound
+        );
+        state.accept(3000, "bob").unwrap();
+        assert!(state.is_owned_by(3000, "bob"));
+    }
+
+    #[test]
+    fn only_owners_pin_ports() {
+        let mut state = held_by("alice", 3000);
+        assert!(!state.set_pinned(3000, "bob", true));
+        assert!(!state.allocations[&3000].pinned);
+        assert!(state.set_pinned(3000, "alice", true));
+        assert!(state.allocations[&3000].pinned);
+    }
+
+    #[test]
+    fn leases_go_stale_and_then_lapse() {
+        let lease = config::current().ports.clone();
+        let start = SystemTime::UNIX_EPOCH + 1000 * HOUR;
+        let state = PortState::default();
+        let mut alloc = allocation("alice", start);
+
+        let stale_at = start + lease.stale_after;
+        assert_eq!(
+            state.reclaim_at(&alloc, stale_at - HOUR),
+            None
+        );
+        assert_eq!(
+            state.reclaim_at(&alloc, stale_at),
+            Some(stale_at + lease.grace)
+        );
+
+        // Being seen in use renews the lease
+        alloc.last_seen = Some(start + HOUR);
+        assert_eq!(state.reclaim_at(&alloc, stale_at), None);
+
+        alloc.pinned = true;
+        assert_eq!(
+            state.reclaim_at(&alloc, stale_at + HOUR),
+            None
+        );
+    }
+
+    #[test]
+    fn collects_lapsed_ports() {
+        let lease = config::current().ports.clone();
+        let start = SystemTime::UNIX_EPOCH + 1000 * HOUR;
+        let now = start + lease.stale_after + lease.grace;
+        let mut state = PortState::default();
+        for port in [3000, 3001, 3002] {
+            state
+                .allocations
+                .insert(port, allocation("alice", start));
+        }
+        state.allocations.get_mut(&3001).unwrap().pinned = true;
+        state
+            .allocations
+            .insert(3003, allocation("bob", now - HOUR));
+
+        // 3002 is in use, 3003 is recent and 3001 is pinned
+        let listening = HashSet::from([3002]);
+        let reclaimed =
+            state.collect_garbage_at(now, &listening);
+        assert_eq!(reclaimed, vec![(3000, "alice".to_string())]);
+
+        let mut left: Vec<u16> =
+            state.allocations.keys().copied().collect();
+        left.sort();
+        assert_eq!(left, [3001, 3002, 3003]);
+        assert_eq!(
+            state.allocations[&3002].last_seen,
+            Some(now)
+        );
+
+        // 3002's lease was renewed, so it outlasts 3003's
+        let later = now + lease.stale_after + lease.grace - HOUR;
+        assert_eq!(
+            state.collect_garbage_at(later, &HashSet::new()),
+            vec![(3003, "bob".to_string())]
+        );
+    }
+}
@@ -1,104 +1,573 @@
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tokio::process::Command;
+use users::os::unix::UserExt;
 
-use crate::state::Process;
-
-pub fn create_log_paths(
-    user: &str,
-    cwd: &Path,
-    cmd: &str,
-) -> (PathBuf, PathBuf) {
-    let slug_cwd = slug::slugify(cwd.to_string_lossy());
-    let slug_cmd = slug::slugify(cmd);
-    let base = PathBuf::from("/home")
-        .join(user)
-        .join(".logs")
-        .join(slug_cwd);
-
-    std::fs::create_dir_all(&base).unwrap_or_default();
-
-    (
-        base.join(format!("{}.stdout", slug_cmd)),
-        base.join(format!("{}.stderr", slug_cmd)),
-    )
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, OFlag};
+use nix::libc::{STDERR_FILENO, STDOUT_FILENO};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::{mkdirat, Mode};
+use nix::unistd::{
+    chdir, dup2, getgrouplist, mkdir, setgid, setgroups, setsid,
+    setuid, Gid, Pid, Uid,
+};
+
+use crate::cgroup;
+use crate::config;
+use crate::quota;
+use crate::reaper;
+use crate::state::{Phase, Process, ProcessSpec, Restarts};
+use crate::tree::Snapshot;
+
+/// Read a process's start time (in clock ticks since boot) from
+/// `/proc/<pid>/stat`.
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat =
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .ok()?;
+    // The command name may contain spaces and parens, so skip
+    // past its closing paren. Start time is field 22 overall,
+    // the 20th after the name.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Whether `pid` is still the process we started, rather than
+/// something else that has since reused the pid.
+pub fn is_alive(pid: u32, started: u64) -> bool {
+    start_time(pid) == Some(started)
+}
+
+/// Parse a signal name, with or without the `SIG` prefix.
+pub fn parse_signal(name: &str) -> std::io::Result<Signal> {
+    let name = name.to_ascii_uppercase();
+    let name = match name.starts_with("SIG") {
+        true => name,
+        false => format!("SIG{}", name),
+    };
+    name.parse().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown signal {}", name),
+        )
+    })
+}
+
+/// PATH for processes started with a clean environment.
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Who a process runs as, looked up before forking since none of
+/// this can be done between fork and exec.
+struct Account {
+    uid: u32,
+    gid: u32,
+    /// Supplementary groups, as `initgroups` would set them.
+    groups: Vec<Gid>,
+    home: PathBuf,
+    shell: PathBuf,
+}
+
+impl Account {
+    fn lookup(name: &str) -> std::io::Result<Self> {
+        let user =
+            users::get_user_by_name(name).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No such user {}", name),
+                )
+            })?;
+        let gid = user.primary_group_id();
+        let groups = getgrouplist(
+            &CString::new(name)
+                .map_err(std::io::Error::other)?,
+            Gid::from_raw(gid),
+        )?;
+
+        Ok(Self {
+            uid: user.uid(),
+            gid,
+            groups,
+            home: user.home_dir().to_path_buf(),
+            shell: user.shell().to_path_buf(),
+        })
+    }
+
+    /// The environment a login shell would start out with.
+    fn login_env(&self, name: &str) -> Vec<(&str, String)> {
+        vec![
+            ("HOME", self.home.to_string_lossy().into_owned()),
+            ("USER", name.into()),
+            ("LOGNAME", name.into()),
+            ("SHELL", self.shell.to_string_lossy().into_owned()),
+            ("PATH", DEFAULT_PATH.into()),
+        ]
+    }
+}
+
+/// Where a process's logs go: a directory under the log root, and
+/// the stem of the file names within it.
+fn log_location(
+    spec: &ProcessSpec,
+) -> std::io::Result<(PathBuf, String)> {
+    let dir = match &spec.logs.dir {
+        // Keep custom directories inside the log root
+        Some(dir)
+            if dir
+                .components()
+                .all(|c| matches!(c, Component::Normal(_))) =>
+        {
+            dir.clone()
+        }
+        Some(dir) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Log directory {} must be a plain relative path",
+                    dir.display()
+                ),
+            ));
+        }
+        None => slug::slugify(spec.cwd.to_string_lossy()).into(),
+    };
+    let stem = match &spec.name {
+        Some(name) => slug::slugify(name),
+        None => slug::slugify(spec.command_line()),
+    };
+    Ok((dir, stem))
+}
+
+/// Create a log root the user couldn't create themselves, such as
+/// `/var/log/hiisi/{user}`, and hand it over to them. This is only
+/// done when nobody but root could have changed the path on the
+/// way there. Anything else is left for the process to create as
+/// the user.
+fn prepare_log_root(
+    root: &Path,
+    account: &Account,
+) -> std::io::Result<()> {
+    let trusted = root.ancestors().skip(1).all(|dir| {
+        match dir.symlink_metadata() {
+            // Still to be created, by root
+            Err(_) => true,
+            Ok(link) => {
+                link.uid() == 0
+                    && dir.metadata().is_ok_and(|meta| {
+                        meta.uid() == 0
+                            && meta.mode() & 0o022 == 0
+                    })
+            }
+        }
+    });
+    if !trusted || root.symlink_metadata().is_ok() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(root)?;
+    lchown(root, Some(account.uid), Some(account.gid))
+}
+
+/// A process's log files, laid out ahead of time so that the child
+/// can open them as the user without allocating.
+struct LogFiles {
+    root: CString,
+    /// Directories under the root, one component each.
+    dirs: Vec<CString>,
+    stdout: CString,
+    stderr: CString,
+    append: bool,
+}
+
+impl LogFiles {
+    fn new(
+        root: &Path,
+        dir: &Path,
+        stem: &str,
+        append: bool,
+    ) -> std::io::Result<Self> {
+        let c_string = |s: &OsStr| {
+            CString::new(s.as_bytes())
+                .map_err(std::io::Error::other)
+        };
+        Ok(Self {
+            root: c_string(root.as_os_str())?,
+            dirs: dir
+                .iter()
+                .map(c_string)
+                .collect::<Result<_, _>>()?,
+            stdout: c_string(
+                format!("{}.stdout", stem).as_ref(),
+            )?,
+            stderr: c_string(
+                format!("{}.stderr", stem).as_ref(),
+            )?,
+            append,
+        })
+    }
+
+    /// Create the directories from the log root down and open both
+    /// files, refusing to follow a symlink anywhere on the way.
+    fn open(&self) -> nix::Result<(OwnedFd, OwnedFd)> {
+        let owned = |fd| unsafe { OwnedFd::from_raw_fd(fd) };
+        let existing = |result| match result {
+            Ok(()) | Err(Errno::EEXIST) => Ok(()),
+            Err(e) => Err(e),
+        };
+        let mode = Mode::from_bits_truncate(0o777);
+        let dir_flags = OFlag::O_DIRECTORY
+            | OFlag::O_NOFOLLOW
+            | OFlag::O_CLOEXEC;
+
+        existing(mkdir(self.root.as_c_str(), mode))?;
+        let mut dir = owned(open(
+            self.root.as_c_str(),
+            dir_flags,
+            Mode::empty(),
+        )?);
+        for name in &self.dirs {
+            let fd = Some(dir.as_raw_fd());
+            existing(mkdirat(fd, name.as_c_str(), mode))?;
+            dir = owned(openat(
+                fd,
+                name.as_c_str(),
+                dir_flags,
+                Mode::empty(),
+            )?);
+        }
+
+        let flags = OFlag::O_WRONLY
+            | OFlag::O_CREAT
+            | OFlag::O_NOFOLLOW
+            | OFlag::O_CLOEXEC
+            | match self.append {
+                true => OFlag::O_APPEND,
+                false => OFlag::O_TRUNC,
+            };
+        let file = |name: &CStr| {
+            openat(
+                Some(dir.as_raw_fd()),
+                name,
+                flags,
+                Mode::from_bits_truncate(0o666),
+            )
+            .map(owned)
+        };
+        Ok((file(&self.stdout)?, file(&self.stderr)?))
+    }
 }
 
 pub async fn spawn_process(
     id: u32,
-    user: String,
-    cmd: String,
-    cwd: PathBuf,
-    env: HashMap<String, String>,
-    restart: bool,
+    spec: ProcessSpec,
 ) -> std::io::Result<Process> {
-    let (stdout_path, stderr_path) =
-        create_log_paths(&user, &cwd, &cmd);
-
-    let stdout_file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&stdout_path)?;
-
-    let stderr_file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&stderr_path)?;
-
-    // Split command into program and args
-    let mut parts = cmd.split_whitespace();
-    let program = parts.next().unwrap();
-    let args: Vec<_> = parts.collect();
-
-    let child = Command::new(program)
-        .args(args)
-        .current_dir(&cwd)
-        .envs(&env) // Here's where we were missing it!
-        .stdout(stdout_file)
-        .stderr(stderr_file)
-        .uid(users::get_user_by_name(&user).unwrap().uid())
-        .spawn()?;
+    // Catch a bad stop signal now rather than when stopping
+    if let Some(signal) = &spec.stop.signal {
+        parse_signal(signal)?;
+    }
+    spec.limits.validate().map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    if !spec.limits.is_empty() && !cgroup::enabled() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Resource limits need cgroup v2",
+        ));
+    }
+    let account = Account::lookup(&spec.user)?;
+    let log_root = config::current()
+        .log_root_for(&spec.user, &account.home);
+    let (dir, stem) = log_location(&spec)?;
+    let base = log_root.join(&dir);
+    let stdout_path = base.join(format!("{}.stdout", stem));
+    let stderr_path = base.join(format!("{}.stderr", stem));
+
+    // Everything under the log root is the user's, so the logs are
+    // created by the process itself once it runs as them
+    prepare_log_root(&log_root, &account)?;
+    let logs =
+        LogFiles::new(&log_root, &dir, &stem, spec.logs.append)?;
+
+    let mut command = if spec.shell {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(spec.argv.join(" "));
+        command
+    } else {
+        let (program, args) =
+            spec.argv.split_first().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Empty command",
+                )
+            })?;
+        let mut command = Command::new(program);
+        command.args(args);
+        command
+    };
+
+    // Entered in the child once it runs as the user, so that it
+    // can't start out somewhere they couldn't get to themselves
+    let cwd = CString::new(spec.cwd.as_os_str().as_bytes())
+        .map_err(std::io::Error::other)?;
+
+    let cgroup = match cgroup::enabled() {
+        true => Some(cgroup::create(
+            account.uid,
+            id,
+            quota::for_user(&spec.user).memory,
+        )?),
+        false => None,
+    };
+    // Removed again if the process doesn't get to start in it
+    let discard = |e: std::io::Error| {
+        if let Some(cgroup) = &cgroup {
+            cgroup::remove(cgroup);
+        }
+        e
+    };
+    if let Some(cgroup) = &cgroup {
+        cgroup::set_limits(cgroup, &spec.limits)
+            .map_err(discard)?;
+    }
+    let procs = cgroup
+        .as_deref()
+        .map(cgroup::procs_path)
+        .transpose()
+        .map_err(discard)?;
+
+    if spec.clean_env {
+        command.env_clear().envs(account.login_env(&spec.user));
+    }
+    command.envs(&spec.env);
+
+    let Account { uid, gid, groups, .. } = account;
+    // Runs in the child between fork and exec, so nothing in here
+    // may allocate. The cgroup has to be joined while still root,
+    // which is why privileges are dropped here rather than through
+    // `Command::uid`, which std applies before these hooks.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(procs) = &procs {
+                cgroup::join(procs)?;
+            }
+            // Lead a session (and so a process group) of its own,
+            // so that the whole tree can be found and stopped
+            setsid()?;
+            setgroups(&groups)?;
+            setgid(Gid::from_raw(gid))?;
+            setuid(Uid::from_raw(uid))?;
+            chdir(cwd.as_c_str())?;
+
+            let (stdout, stderr) = logs.open()?;
+            dup2(stdout.as_raw_fd(), STDOUT_FILENO)?;
+            dup2(stderr.as_raw_fd(), STDERR_FILENO)?;
+            Ok(())
+        });
+    }
 
+    let child = command.spawn().map_err(discard)?;
+
+    let pid = child.id().unwrap_or_default();
+    let start_time = start_time(pid).unwrap_or_default();
+    reaper::watch(id, pid, child);
     Ok(Process {
         id,
-        user,
-        cmd,
-        cwd,
+        spec,
         started_at: SystemTime::now(),
-        restart,
-        child,
+        pid,
+        start_time,
         stdout_path,
         stderr_path,
-        env,
+        restarts: Restarts::default(),
+        cgroup,
+        exit: None,
+        phase: Phase::Running,
+        signalled: None,
     })
 }
 
+/// Send `signal` to every process in the tree. Returns how many
+/// were signalled.
+pub fn signal_tree(
+    process: &Process,
+    signal: Signal,
+) -> std::io::Result<usize> {
+    let members = process.tree(&Snapshot::take());
+
+    let cgroup = process
+        .cgroup
+        .as_deref()
+        .filter(|_| signal == Signal::SIGKILL);
+    if cgroup.is_some_and(|cgroup| cgroup::kill(cgroup).is_ok())
+    {
+        return Ok(members.len());
+    }
+
+    for &pid in &members {
+        match kill(Pid::from_raw(pid as i32), signal) {
+            // Exited in the meantime
+            Ok(()) | Err(Errno::ESRCH) => (),
+            Err(e) => return Err(std::io::Error::other(e)),
+        }
+    }
+    Ok(members.len())
+}
+
+/// Wait up to `timeout` for the whole tree to exit. The leader is
+/// reaped by its watcher as soon as it exits.
+async fn wait_exit(
+    process: &Process,
+    timeout: Duration,
+) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if process.tree(&Snapshot::take()).is_empty() {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Stop a process and everything it spawned by escalating from
+/// its stop signal to SIGTERM and finally SIGKILL.
 pub async fn stop_process(
-    process: &mut Process,
+    process: &Process,
 ) -> std::io::Result<()> {
-    // Try SIGINT first
-    process.child.start_kill()?;
-
-    // Wait up to 15 seconds for graceful shutdown
-    tokio::select! {
-        _ = process.child.wait() => Ok(()),
-        _ = tokio::time::sleep(std::time::Duration::from_secs(15)) => {
-            // Try SIGTERM
-            process.child.kill().await?;
-
-            // Wait another 15 seconds
-            tokio::select! {
-                _ = process.child.wait() => Ok(()),
-                _ = tokio::time::sleep(std::time::Duration::from_secs(15)) => {
-                    // Force SIGKILL
-                    nix::sys::signal::kill(
-                        nix::unistd::Pid::from_raw(process.child.id().unwrap() as i32),
-                        nix::sys::signal::Signal::SIGKILL,
-                    ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                    Ok(())
-                }
-            }
+    let policy = config::current().stop.with(&process.spec.stop);
+    let first = parse_signal(&policy.signal)?;
+
+    let mut ladder = vec![(first, policy.timeout)];
+    if first != Signal::SIGTERM && first != Signal::SIGKILL {
+        ladder.push((Signal::SIGTERM, policy.term_timeout));
+    }
+    if first != Signal::SIGKILL {
+        ladder.push((Signal::SIGKILL, policy.kill_timeout));
+    }
+
+    for (signal, timeout) in ladder {
+        let signalled = signal_tree(process, signal)?;
+        if signalled == 0 {
+            break;
+        }
+
+        tracing::info!(
+            "Sent {} to process {} ({} pids)",
+            signal,
+            process.id,
+            signalled
+        );
+        if wait_exit(process, timeout).await {
+            break;
+        }
+    }
+
+    if process.tree(&Snapshot::take()).is_empty() {
+        if let Some(cgroup) = &process.cgroup {
+            cgroup::remove(cgroup);
         }
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("Process {} survived SIGKILL", process.id),
+        ))
+    }
+}
+
+/// Kill whatever a process that exited on its own left behind, so
+/// that restarting it doesn't pile up strays.
+pub fn kill_leftovers(process: &Process) {
+    match signal_tree(process, Signal::SIGKILL) {
+        Ok(0) => (),
+        Ok(n) => tracing::info!(
+            "Killed {} leftover pids of process {}",
+            n,
+            process.id
+        ),
+        Err(e) => tracing::error!(
+            "Couldn't kill leftovers of process {}: {}",
+            process.id,
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn log_files(root: &Path, dir: &str) -> LogFiles {
+        LogFiles::new(root, Path::new(dir), "app", true).unwrap()
+    }
+
+    #[test]
+    fn creates_log_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("logs");
+
+        log_files(&root, "srv/app").open().unwrap();
+        assert!(root.join("srv/app/app.stdout").is_file());
+        assert!(root.join("srv/app/app.stderr").is_file());
+    }
+
+    #[test]
+    fn refuses_symlinked_log_root() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("elsewhere");
+        std::fs::create_dir(&target).unwrap();
+        let root = temp.path().join("logs");
+        symlink(&target, &root).unwrap();
+
+        assert!(log_files(&root, "srv").open().is_err());
+        assert!(!target.join("srv").exists());
+    }
+
+    #[test]
+    fn refuses_symlinked_log_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("elsewhere");
+        std::fs::create_dir(&target).unwrap();
+        let root = temp.path().join("logs");
+        std::fs::create_dir(&root).unwrap();
+        symlink(&target, root.join("srv")).unwrap();
+
+        assert!(log_files(&root, "srv/app").open().is_err());
+        assert!(!target.join("app").exists());
+    }
+
+    #[test]
+    fn refuses_symlinked_log_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path().join("elsewhere");
+        std::fs::write(&target, "").unwrap();
+        let root = temp.path().join("logs");
+        std::fs::create_dir_all(root.join("srv")).unwrap();
+        symlink(&target, root.join("srv/app.stdout")).unwrap();
+
+        assert!(log_files(&root, "srv").open().is_err());
+    }
+
+    #[test]
+    fn leaves_log_root_under_writable_dir_to_user() {
+        // Temporary directories live under the world-writable /tmp
+        let temp = tempfile::tempdir().unwrap();
+        let account = Account {
+            uid: 65534,
+            gid: 65534,
+            groups: Vec::new(),
+            home: temp.path().into(),
+            shell: "/bin/sh".into(),
+        };
+
+        let root = temp.path().join("logs");
+        prepare_log_root(&root, &account).unwrap();
+        assert!(!root.exists());
     }
 }
@@ -0,0 +1,220 @@
+use hiisi_common::protocol::{
+    format_size, ErrorKind, ProcessInfo, RequestError,
+    ResourceLimits,
+};
+
+use crate::config::{self, QuotaLimits, Quotas};
+
+/// The limits that apply to `user`.
+pub fn for_user(user: &str) -> QuotaLimits {
+    let groups: Vec<String> = users::get_user_by_name(user)
+        .and_then(|u| {
+            users::get_user_groups(user, u.primary_group_id())
+        })
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|g| Some(g.name().to_str()?.to_owned()))
+        .collect();
+    limits_for(&config::current().quotas, user, &groups)
+}
+
+/// The limits `quotas` set for `user`, a member of `groups`. Each
+/// limit comes from their own entry if it sets it, otherwise from
+/// the most generous of their groups' entries that do, otherwise
+/// from the default.
+fn limits_for(
+    quotas: &Quotas,
+    user: &str,
+    groups: &[String],
+) -> QuotaLimits {
+    let own =
+        quotas.users.get(user).copied().unwrap_or_default();
+    let groups: Vec<QuotaLimits> = groups
+        .iter()
+        .filter_map(|g| quotas.groups.get(g))
+        .copied()
+        .collect();
+    let resolve = |get: fn(&QuotaLimits) -> Option<u64>| {
+        get(&own)
+            .or_else(|| groups.iter().filter_map(get).max())
+            .or(get(&quotas.default))
+    };
+
+    QuotaLimits {
+        processes: resolve(|q| q.processes),
+        ports: resolve(|q| q.ports),
+        memory: resolve(|q| q.memory),
+    }
+}
+
+/// Refuse to take `adding` more of something the user already has
+/// `used` of, if that would go over `limit`.
+pub fn check(
+    what: &str,
+    used: u64,
+    adding: u64,
+    limit: Option<u64>,
+) -> Result<(), RequestError> {
+    match limit {
+        Some(limit) if used + adding > limit => {
+            Err(exceeded(format!(
+                "Quota of {} {} exceeded: {} in use, {} more requested",
+                limit, what, used, adding
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Memory in use by `processes`, from their cgroups where they have
+/// one.
+pub fn memory_used<'a>(
+    processes: impl IntoIterator<Item = &'a ProcessInfo>,
+) -> u64 {
+    processes
+        .into_iter()
+        .map(|p| {
+            p.usage
+                .as_ref()
+                .and_then(|u| u.memory)
+                .unwrap_or(p.rss)
+        })
+        .sum()
+}
+
+/// Refuse a new process once the user's memory quota is used up,
+/// or if its own limit alone wouldn't fit in the quota.
+pub fn check_memory(
+    used: u64,
+    limits: &ResourceLimits,
+    limit: Option<u64>,
+) -> Result<(), RequestError> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    if used >= limit {
+        return Err(exceeded(format!(
+            "Memory quota of {} used up ({} in use)",
+            format_size(limit),
+            format_size(used)
+        )));
+    }
+    match limits.memory_max {
+        Some(max) if max > limit => Err(exceeded(format!(
+            "Memory limit of {} is over the memory quota of {}",
+            format_size(max),
+            format_size(limit)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn exceeded(message: String) -> RequestError {
+    RequestError::new(ErrorKind::QuotaExceeded, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(
+        processes: Option<u64>,
+        ports: Option<u64>,
+        memory: Option<u64>,
+    ) -> QuotaLimits {
+        QuotaLimits { processes, ports, memory }
+    }
+
+    fn quotas() -> Quotas {
+        Quotas {
+            default: limits(Some(5), Some(2), Some(100)),
+            users: [(
+                "alice".into(),
+                limits(Some(1), None, None),
+            )]
+            .into(),
+            groups: [
+                (
+                    "staff".into(),
+                    limits(None, Some(10), Some(200)),
+                ),
+                ("ops".into(), limits(Some(50), Some(20), None)),
+            ]
+            .into(),
+        }
+    }
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|g| g.to_string()).collect()
+    }
+
+    #[test]
+    fn own_entry_wins() {
+        let resolved =
+            limits_for(&quotas(), "alice", &groups(&["ops"]));
+        assert_eq!(resolved.processes, Some(1));
+    }
+
+    #[test]
+    fn most_generous_group_beats_default() {
+        let resolved = limits_for(
+            &quotas(),
+            "bob",
+            &groups(&["staff", "ops"]),
+        );
+        assert_eq!(resolved.processes, Some(50));
+        assert_eq!(resolved.ports, Some(20));
+        assert_eq!(resolved.memory, Some(200));
+    }
+
+    #[test]
+    fn default_fills_in_the_rest() {
+        let quotas = quotas();
+        let resolved =
+            limits_for(&quotas, "alice", &groups(&["staff"]));
+        assert_eq!(resolved.processes, Some(1));
+        assert_eq!(resolved.ports, Some(10));
+        assert_eq!(resolved.memory, Some(200));
+
+        let resolved = limits_for(&quotas, "carol", &[]);
+        assert_eq!(resolved.processes, Some(5));
+        assert_eq!(resolved.ports, Some(2));
+        assert_eq!(resolved.memory, Some(100));
+    }
+
+    #[test]
+    fn unset_everywhere_is_unlimited() {
+        let resolved = limits_for(
+            &Quotas::default(),
+            "alice",
+            &groups(&["ops"]),
+        );
+        assert_eq!(resolved.processes, None);
+        assert_eq!(resolved.ports, None);
+        assert_eq!(resolved.memory, None);
+    }
+
+    #[test]
+    fn check_allows_up_to_limit() {
+        assert!(check("ports", 2, 1, Some(3)).is_ok());
+        assert!(check("ports", 100, 1, None).is_ok());
+
+        let e = check("ports", 3, 1, Some(3)).unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::QuotaExceeded));
+        assert!(check("ports", 0, 4, Some(3)).is_err());
+    }
+
+    #[test]
+    fn check_memory_refuses_full_quota_and_big_limits() {
+        let none = ResourceLimits::default();
+        assert!(check_memory(99, &none, Some(100)).is_ok());
+        assert!(check_memory(100, &none, Some(100)).is_err());
+        assert!(check_memory(u64::MAX, &none, None).is_ok());
+
+        let big = ResourceLimits {
+            memory_max: Some(200),
+            ..ResourceLimits::default()
+        };
+        assert!(check_memory(0, &big, Some(100)).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use nix::libc;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use tokio::io::unix::AsyncFd;
+use tokio::process::Child;
+use tokio::sync::mpsc::{
+    unbounded_channel, UnboundedReceiver, UnboundedSender,
+};
+
+use crate::config;
+use crate::process::is_alive;
+use crate::state::Exit;
+
+/// Something that happened to a managed process. Both carry the
+/// pid of the incarnation they're about, so that events outliving
+/// it (after a stop or restart) can be told apart and dropped.
+#[derive(Debug)]
+pub enum Event {
+    Exited { id: u32, pid: u32, exit: Exit },
+    RestartDue { id: u32, pid: u32 },
+}
+
+static EVENTS: OnceLock<UnboundedSender<Event>> =
+    OnceLock::new();
+
+/// Set up the event channel. Called once, before anything is
+/// spawned or adopted.
+pub fn init() -> UnboundedReceiver<Event> {
+    let (tx, rx) = unbounded_channel();
+    EVENTS.set(tx).expect("reaper initialized twice");
+    rx
+}
+
+fn send(event: Event) {
+    // Only fails once the daemon is shutting down
+    if let Some(events) = EVENTS.get() {
+        events.send(event).ok();
+    }
+}
+
+/// Wait for a child we spawned, reaping it the moment it exits.
+pub fn watch(id: u32, pid: u32, mut child: Child) {
+    tokio::spawn(async move {
+        let exit = match child.wait().await {
+            Ok(status) => match (status.code(), status.signal())
+            {
+                (Some(code), _) => Exit::Code(code),
+                (None, Some(signal)) => Exit::Signal(signal),
+                (None, None) => Exit::Unknown,
+            },
+            Err(e) => {
+                tracing::error!(
+                    "Couldn't wait for process {}: {}",
+                    id,
+                    e
+                );
+                Exit::Unknown
+            }
+        };
+        send(Event::Exited { id, pid, exit });
+    });
+}
+
+/// Watch a process left running by a previous daemon. It isn't our
+/// child, so all we learn is that it's gone, not how it ended.
+pub fn adopt(id: u32, pid: u32, start_time: u64) {
+    tokio::spawn(async move {
+        match pidfd_open(pid) {
+            // Checked after opening, so the pidfd is known to refer
+            // to our process rather than one that reused its pid
+            Ok(fd) if is_alive(pid, start_time) => {
+                // A pidfd turns readable once the process exits
+                match AsyncFd::new(fd) {
+                    Ok(fd) => {
+                        fd.readable().await.ok();
+                    }
+                    Err(_) => poll_exit(pid, start_time).await,
+                }
+            }
+            Ok(_) => (),
+            // Kernels before 5.3 have no pidfds
+            Err(_) => poll_exit(pid, start_time).await,
+        }
+        send(Event::Exited { id, pid, exit: Exit::Unknown });
+    });
+}
+
+async fn poll_exit(pid: u32, start_time: u64) {
+    while is_alive(pid, start_time) {
+        tokio::time::sleep(config::current().intervals.monitor)
+            .await;
+    }
+}
+
+fn pidfd_open(pid: u32) -> std::io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_open,
+            pid as libc::pid_t,
+            0,
+        )
+    };
+    match fd {
+        -1 => Err(std::io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+    }
+}
+
+/// Send `RestartDue` for the incarnation `pid` of process `id` at
+/// `at`.
+pub fn schedule_restart(id: u32, pid: u32, at: SystemTime) {
+    tokio::spawn(async move {
+        let delay = at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        tokio::time::sleep(delay).await;
+        send(Event::RestartDue { id, pid });
+    });
+}
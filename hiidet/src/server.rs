@@ -1,19 +1,40 @@
-use hiisi_common::frame::{read_frame, write_frame};
+use hiisi_common::frame::{read_frame, write_frame, FrameError};
+use hiisi_common::manifest::{LogSettings, Service};
 use hiisi_common::protocol::{
-    Command, Message, Response, ResponseData,
+    capability, AdminCommand, AdminTarget, ApplyReport, Command,
+    ErrorKind, Hello, HelloAck, LegacyResponse, Message,
+    ProcessRef, QuotaReport, QuotaUsage, RequestError, Response,
+    ResponseData, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
 };
 
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use tokio::net::UnixListener;
-use tokio::sync::Mutex;
+use serde::de::DeserializeOwned;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{watch, Mutex};
 
+use crate::auth::{AuthError, Credentials};
+use crate::cgroup;
+use crate::config;
+use crate::metrics;
 use crate::monitor::SystemMonitor;
+use crate::persist::PersistError;
 use crate::ports::PortState;
-use crate::process::{spawn_process, stop_process};
-use crate::state::State;
+use crate::process::{
+    kill_leftovers, parse_signal, signal_tree, spawn_process,
+    stop_process,
+};
+use crate::quota;
+use crate::reaper::{self, Event};
+use crate::state::{Phase, ProcessSpec, State, Stopped};
+use crate::tree::Snapshot;
 
 pub struct Server {
     state: Arc<Mutex<State>>,
@@ -22,88 +43,109 @@ pub struct Server {
 }
 
 impl Server {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, PersistError> {
+        let events = reaper::init();
+        let mut state = State::load()?;
+        // Pick up where the previous daemon left off: watch what's
+        // still running, including processes that exited while it
+        // was down, and reschedule pending restarts
+        let now = SystemTime::now();
+        let mut stopping = Vec::new();
+        for process in state.processes.values_mut() {
+            if let Phase::Stopping { restart } = process.phase {
+                stopping.push((process.id, restart));
+            }
+            match (process.exit, process.phase) {
+                (None, _) => reaper::adopt(
+                    process.id,
+                    process.pid,
+                    process.start_time,
+                ),
+                (Some(_), Phase::Stopping { .. }) => (),
+                (Some(_), _) => {
+                    if let Some(at) = process.restart_at(now) {
+                        reaper::schedule_restart(
+                            process.id,
+                            process.pid,
+                            at,
+                        );
+                    }
+                }
+            }
+        }
+
         let server = Self {
-            state: Arc::new(Mutex::new(State::new())),
-            ports: Arc::new(Mutex::new(PortState::load())),
+            state: Arc::new(Mutex::new(state)),
+            ports: Arc::new(Mutex::new(PortState::load()?)),
             monitor: Arc::new(Mutex::new(SystemMonitor::new())),
         };
 
-        // Start process monitoring task
+        tokio::spawn(handle_events(
+            events,
+            server.state.clone(),
+        ));
+
+        // Finish the stops the previous daemon didn't get to
         let state = server.state.clone();
+        tokio::spawn(async move {
+            let mut locked = state.lock().await;
+            for (id, restart) in stopping {
+                stop_later(&state, &mut locked, id, restart);
+            }
+        });
+
+        // Keep CPU usage figures current between status requests
+        let monitor = server.monitor.clone();
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(
-                    std::time::Duration::from_secs(1),
+                    config::current().intervals.monitor,
                 )
                 .await;
-                let mut state = state.lock().await;
-
-                // Check all processes
-                let mut to_restart = Vec::new();
-                for process in state.processes.values_mut() {
-                    if process.restart {
-                        match process.child.try_wait() {
-                            Ok(Some(_)) => {
-                                // Process exited, needs restart
-                                to_restart.push(process.id);
-                            }
-                            Ok(None) => (), // Still running
-                            Err(e) => tracing::error!(
-                                "Error checking process {}: {}",
-                                process.id,
-                                e
-                            ),
-                        }
-                    }
-                }
-
-                // Restart processes that died
-                for id in to_restart {
-                    let old_process =
-                        state.processes.get(&id).unwrap();
-                    match spawn_process(
-                        id,
-                        old_process.user.clone(),
-                        old_process.cmd.clone(),
-                        old_process.cwd.clone(),
-                        old_process.env.clone(),
-                        true,
-                    )
-                    .await
-                    {
-                        Ok(new_process) => {
-                            state
-                                .processes
-                                .insert(id, new_process);
-                            tracing::info!(
-                                "Restarted process {}",
-                                id
-                            );
-                        }
-                        Err(e) => tracing::error!(
-                            "Failed to restart process {}: {}",
-                            id,
-                            e
-                        ),
-                    }
-                }
+                monitor.lock().await.refresh();
             }
         });
 
-        // Start port state saving task
+        // Reclaim ports nothing has listened on for too long
         let ports = server.ports.clone();
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(
-                    std::time::Duration::from_secs(30),
+                    config::current().intervals.port_gc,
                 )
                 .await;
-                ports.lock().await.check_save();
+                let reclaimed =
+                    ports.lock().await.collect_garbage();
+                for (port, user) in reclaimed {
+                    tracing::info!(
+                        "Reclaimed idle port {} of {}",
+                        port,
+                        user
+                    );
+                }
             }
         });
 
-        server
+        Ok(server)
+    }
+
+    /// Serve Prometheus metrics over HTTP on `addr`.
+    pub async fn run_metrics(
+        &self,
+        addr: SocketAddr,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!(
+            "Serving metrics on http://{}/metrics",
+            addr
+        );
+        metrics::serve(
+            listener,
+            self.state.clone(),
+            self.ports.clone(),
+            self.monitor.clone(),
+        )
+        .await
     }
 
     pub async fn run(
@@ -122,149 +164,1259 @@ impl Server {
 
         loop {
             let (socket, _) = listener.accept().await?;
-            let (mut reader, mut writer) = socket.into_split();
+            let creds = match Credentials::from_stream(&socket) {
+                Ok(creds) => creds,
+                Err(e) => {
+                    tracing::warn!(
+                        "Rejecting connection: {}",
+                        e
+                    );
+                    tokio::spawn(refuse(socket, e));
+                    continue;
+                }
+            };
+            tracing::debug!(
+                "Connection from {} (uid {}, gid {}, pid {:?})",
+                creds.user,
+                creds.uid,
+                creds.gid,
+                creds.pid
+            );
+            tokio::spawn(serve(
+                socket,
+                creds,
+                self.state.clone(),
+                self.ports.clone(),
+                self.monitor.clone(),
+            ));
+        }
+    }
+}
 
-            let state = self.state.clone();
-            let ports = self.ports.clone();
-            let monitor = self.monitor.clone();
+/// Talk to one client until it hangs up.
+async fn serve(
+    socket: UnixStream,
+    creds: Credentials,
+    state: Arc<Mutex<State>>,
+    ports: Arc<Mutex<PortState>>,
+    monitor: Arc<Mutex<SystemMonitor>>,
+) {
+    let (mut reader, mut writer) = socket.into_split();
 
-            tokio::spawn(async move {
-                while let Ok(msg) =
-                    read_frame::<_, Message>(&mut reader).await
-                {
-                    let response = handle_message(
-                        msg, &state, &ports, &monitor,
+    let hello = match read_frame::<_, Hello>(&mut reader).await {
+        Ok(hello) => hello,
+        // Clients from before the handshake start with a request,
+        // but can still read an error
+        Err(FrameError::Decode(e)) => {
+            tracing::warn!(
+                "Hanging up on {} (pid {:?}), who didn't open with a \
+                 handshake: {}",
+                creds.user,
+                creds.pid,
+                e
+            );
+            let error = LegacyResponse::Error(format!(
+                "hiidet speaks protocol {}; upgrade hiisi",
+                MIN_PROTOCOL_VERSION
+            ));
+            write_frame(&mut writer, &error).await.ok();
+            return;
+        }
+        Err(_) => return,
+    };
+    if !greet(&hello, &creds, &mut writer).await {
+        return;
+    }
+    // Clients that don't know about background stops expect stops
+    // to be done by the time they're answered
+    let background_stop = hello
+        .capabilities
+        .iter()
+        .any(|c| c == capability::BACKGROUND_STOP);
+
+    while let Some(mut msg) =
+        next_frame::<Message>(&mut reader, &mut writer, &creds)
+            .await
+    {
+        if let (
+            false,
+            Command::Stop { wait, .. }
+            | Command::Admin(AdminCommand::Stop { wait, .. }),
+        ) = (background_stop, &mut msg.cmd)
+        {
+            *wait = true;
+        }
+
+        let response = match creds.verify(&msg.user) {
+            Ok(()) => {
+                handle_message(
+                    msg, &creds, &state, &ports, &monitor,
+                )
+                .await
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Rejecting message from pid {:?}: {}",
+                    creds.pid,
+                    e
+                );
+                Response::error(
+                    ErrorKind::PermissionDenied,
+                    e.to_string(),
+                )
+            }
+        };
+        if !reply(&mut writer, &response).await {
+            break;
+        }
+    }
+}
+
+/// Answer a client's `Hello`, returning whether the two of us have
+/// a protocol version in common.
+async fn greet(
+    hello: &Hello,
+    creds: &Credentials,
+    writer: &mut OwnedWriteHalf,
+) -> bool {
+    let ack = hello_ack(hello);
+    match ack.version {
+        Some(version) => tracing::debug!(
+            "pid {:?} speaks protocol {}",
+            creds.pid,
+            version
+        ),
+        None => tracing::warn!(
+            "Turning away {} (pid {:?}), whose protocol versions \
+             {}-{} we don't speak",
+            creds.user,
+            creds.pid,
+            hello.min_version,
+            hello.version
+        ),
+    }
+    write_frame(writer, &ack).await.is_ok()
+        && ack.version.is_some()
+}
+
+/// The daemon's half of the handshake.
+fn hello_ack(hello: &Hello) -> HelloAck {
+    let mut capabilities =
+        vec![capability::BACKGROUND_STOP.into()];
+    if cgroup::enabled() {
+        capabilities.push(capability::CGROUPS.into());
+    }
+    HelloAck {
+        version: hello.negotiate(),
+        daemon_version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        capabilities,
+    }
+}
+
+/// Answer the request of a client we couldn't identify with why
+/// it was refused, rather than leave it to guess from a dropped
+/// connection.
+async fn refuse(socket: UnixStream, error: AuthError) {
+    let (mut reader, mut writer) = socket.into_split();
+    let Ok(hello) = read_frame::<_, Hello>(&mut reader).await
+    else {
+        return;
+    };
+    let ack = hello_ack(&hello);
+    if ack.version.is_none()
+        || write_frame(&mut writer, &ack).await.is_err()
+    {
+        return;
+    }
+    if read_frame::<_, Message>(&mut reader).await.is_ok() {
+        let response = Response::error(
+            ErrorKind::PermissionDenied,
+            error.to_string(),
+        );
+        reply(&mut writer, &response).await;
+    }
+}
+
+/// Read the next frame from a client. Malformed ones are answered
+/// with an error and skipped; none comes once the client has hung
+/// up or sent something we can't get past.
+async fn next_frame<T: DeserializeOwned>(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    creds: &Credentials,
+) -> Option<T> {
+    loop {
+        match read_frame(reader).await {
+            Ok(frame) => return Some(frame),
+            // The whole frame was read, so the next one can still
+            // be understood
+            Err(FrameError::Decode(e)) => {
+                tracing::warn!(
+                    "Malformed request from {} (pid {:?}): {}",
+                    creds.user,
+                    creds.pid,
+                    e
+                );
+                let response = Response::Error(
+                    RequestError::new(
+                        ErrorKind::InvalidRequest,
+                        "Malformed request",
                     )
-                    .await;
-                    if write_frame(&mut writer, &response)
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    }
+                    .details(e),
+                );
+                if !reply(writer, &response).await {
+                    return None;
                 }
-            });
+            }
+            // Not worth reading 16MB+ of whatever it is to stay in
+            // step
+            Err(e @ FrameError::TooLarge(_)) => {
+                tracing::warn!(
+                    "Dropping connection from {} (pid {:?}): {}",
+                    creds.user,
+                    creds.pid,
+                    e
+                );
+                let response = Response::error(
+                    ErrorKind::InvalidRequest,
+                    e.to_string(),
+                );
+                reply(writer, &response).await;
+                return None;
+            }
+            // Hung up
+            Err(_) => return None,
         }
     }
 }
 
+/// Send `response`, returning whether the connection is still
+/// good for more.
+async fn reply(
+    writer: &mut OwnedWriteHalf,
+    response: &Response,
+) -> bool {
+    match write_frame(writer, response).await {
+        Ok(()) => true,
+        // Nothing was written, so there's still room for an error
+        Err(FrameError::Encode(e)) => {
+            tracing::error!("Couldn't encode response: {}", e);
+            let response = Response::error(
+                ErrorKind::Internal,
+                "Couldn't encode response",
+            );
+            write_frame(writer, &response).await.is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
 async fn handle_message(
     msg: Message,
+    creds: &Credentials,
     state: &Arc<Mutex<State>>,
     ports: &Arc<Mutex<PortState>>,
     monitor: &Arc<Mutex<SystemMonitor>>,
 ) -> Response {
     match msg.cmd {
-        Command::Run { cmd, cwd, env, restart } => {
+        Command::Run {
+            name,
+            argv,
+            shell,
+            cwd,
+            env,
+            clean_env,
+            restart,
+            backoff,
+            stop,
+            limits,
+        } => {
             let mut state = state.lock().await;
+            if let Some(Err(e)) = name
+                .as_ref()
+                .map(|name| state.check_name(&creds.user, name))
+            {
+                return Response::Error(e);
+            }
+            let quota = quota::for_user(&creds.user);
+            let memory = match quota.memory {
+                Some(_) => {
+                    let monitor = monitor.lock().await;
+                    state.memory_used(&creds.user, &monitor)
+                }
+                None => 0,
+            };
+            let within_quota = quota::check(
+                "processes",
+                state.count(&creds.user),
+                1,
+                quota.processes,
+            )
+            .and_then(|()| {
+                quota::check_memory(
+                    memory,
+                    &limits,
+                    quota.memory,
+                )
+            });
+            if let Err(e) = within_quota {
+                return Response::Error(e);
+            }
             let id = state.next_id();
 
-            match spawn_process(
-                id, msg.user, cmd, cwd, env, restart,
-            )
-            .await
-            {
+            let spec = ProcessSpec {
+                user: creds.user.clone(),
+                name,
+                argv,
+                shell,
+                cwd,
+                env,
+                clean_env,
+                restart,
+                backoff,
+                stop,
+                limits,
+                logs: LogSettings::default(),
+                service: None,
+            };
+
+            match spawn_process(id, spec).await {
                 Ok(process) => {
                     state.add_process(process);
                     Response::Ok(ResponseData::ProcessStarted {
                         id,
                     })
                 }
-                Err(e) => Response::Error(format!(
-                    "Failed to start process: {}",
-                    e
+                Err(e) => Response::Error(spawn_failed(
+                    "Failed to start process".into(),
+                    e,
                 )),
             }
         }
 
-        Command::Stop { id } => {
-            let mut state = state.lock().await;
+        Command::Stop { process, wait } => {
+            let mut locked = state.lock().await;
+            let id = locked.resolve(&creds.user, &process);
 
-            match state.get_process(id) {
-                Some(process) if process.user == msg.user => {
-                    if let Some(mut process) =
-                        state.remove_process(id)
-                    {
-                        match stop_process(&mut process).await {
-                            Ok(_) => Response::Ok(
-                                ResponseData::ProcessStopped,
-                            ),
-                            Err(e) => Response::Error(format!(
-                                "Failed to stop process: {}",
-                                e
-                            )),
-                        }
-                    } else {
-                        Response::Error(
-                            "Process not found".into(),
-                        )
-                    }
+            let id = match id
+                .and_then(|id| locked.get_process(id))
+            {
+                Some(process)
+                    if process.spec.user == creds.user =>
+                {
+                    process.id
+                }
+                Some(_) => {
+                    return Response::error(
+                        ErrorKind::PermissionDenied,
+                        "Not authorized to stop this process",
+                    );
                 }
-                Some(_) => Response::Error(
-                    "Not authorized to stop this process".into(),
-                ),
                 None => {
-                    Response::Error("Process not found".into())
+                    return Response::error(
+                        ErrorKind::NotFound,
+                        "Process not found",
+                    );
                 }
-            }
+            };
+            let stopped =
+                stop_later(state, &mut locked, id, false);
+            drop(locked);
+            stop_response(stopped, wait).await
         }
 
-        Command::Logs { id } => {
+        Command::Logs { process } => {
             let state = state.lock().await;
+            let id = state.resolve(&creds.user, &process);
 
-            match state.get_process(id) {
-                Some(process) if process.user == msg.user => {
+            match id.and_then(|id| state.get_process(id)) {
+                Some(process)
+                    if process.spec.user == creds.user =>
+                {
                     Response::Ok(ResponseData::Logs {
                         stdout: process.stdout_path.clone(),
                         stderr: process.stderr_path.clone(),
                     })
                 }
-                Some(_) => Response::Error(
-                    "Not authorized to view these logs".into(),
+                Some(_) => Response::error(
+                    ErrorKind::PermissionDenied,
+                    "Not authorized to view these logs",
                 ),
-                None => {
-                    Response::Error("Process not found".into())
+                None => Response::error(
+                    ErrorKind::NotFound,
+                    "Process not found",
+                ),
+            }
+        }
+
+        Command::Signal { process, signal } => {
+            let signal = match parse_signal(&signal) {
+                Ok(signal) => signal,
+                Err(e) => {
+                    return Response::error(
+                        ErrorKind::InvalidRequest,
+                        e.to_string(),
+                    );
                 }
+            };
+            let mut state = state.lock().await;
+            let id = state.resolve(&creds.user, &process);
+
+            match id.and_then(|id| state.processes.get_mut(&id))
+            {
+                Some(process)
+                    if process.spec.user == creds.user =>
+                {
+                    match signal_tree(process, signal) {
+                        Ok(0) => Response::error(
+                            ErrorKind::InvalidRequest,
+                            "Process is not running",
+                        ),
+                        Ok(pids) => {
+                            process.signalled =
+                                Some(signal as i32);
+                            state.save();
+                            Response::Ok(
+                                ResponseData::Signalled { pids },
+                            )
+                        }
+                        Err(e) => Response::Error(
+                            RequestError::new(
+                                ErrorKind::Internal,
+                                "Failed to signal process",
+                            )
+                            .details(e),
+                        ),
+                    }
+                }
+                Some(_) => Response::error(
+                    ErrorKind::PermissionDenied,
+                    "Not authorized to signal this process",
+                ),
+                None => Response::error(
+                    ErrorKind::NotFound,
+                    "Process not found",
+                ),
             }
         }
 
         Command::Status => {
-            let mut state = state.lock().await;
+            let state = state.lock().await;
+            let monitor = monitor.lock().await;
             Response::Ok(ResponseData::Status(
-                state.list_processes(),
+                state.list_processes(&monitor),
             ))
         }
 
         Command::PortLookup { user } => {
-            let ports = ports.lock().await;
-            Response::Ok(ResponseData::PortList(
-                ports.lookup(user),
-            ))
+            let owners = state.lock().await.pid_owners();
+            let mut list = ports.lock().await.lookup(user);
+            for port in &mut list {
+                port.process = port
+                    .pid
+                    .and_then(|pid| owners.get(&pid))
+                    .cloned();
+            }
+            Response::Ok(ResponseData::PortList(list))
         }
 
         Command::PortAllocate { port } => {
             let mut ports = ports.lock().await;
-            match ports.allocate(msg.user, port) {
+            let limit = quota::for_user(&creds.user).ports;
+            if let Err(e) = quota::check(
+                "ports",
+                ports.count(&creds.user),
+                1,
+                limit,
+            ) {
+                return Response::Error(e);
+            }
+            match ports.allocate(creds.user.clone(), port) {
                 Some(port) => {
                     Response::Ok(ResponseData::PortAllocated {
                         port,
                     })
                 }
-                None => Response::Error(
-                    "Port allocation failed".into(),
+                None => Response::error(
+                    ErrorKind::PortUnavailable,
+                    match port {
+                        Some(port) => format!(
+                            "Port {} is taken or out of range",
+                            port
+                        ),
+                        None => "No free port found".into(),
+                    },
                 ),
             }
         }
 
-        Command::PortFree { port } => {
+        Command::Apply { services, env } => {
+            let memory = {
+                let state = state.lock().await;
+                let monitor = monitor.lock().await;
+                state.memory_used(&creds.user, &monitor)
+            };
+            match apply(
+                &creds.user,
+                services,
+                env,
+                memory,
+                state,
+                ports,
+            )
+            .await
+            {
+                Ok(report) => {
+                    Response::Ok(ResponseData::Applied(report))
+                }
+                Err(e) => Response::Error(e),
+            }
+        }
+
+        Command::PortPin { port, pinned } => {
             let mut ports = ports.lock().await;
-            if ports.free(port) {
-                Response::Ok(ResponseData::PortFreed)
+            if ports.set_pinned(port, &creds.user, pinned) {
+                Response::Ok(ResponseData::PortPinned)
             } else {
-                Response::Error(
-                    "Port not found or not owned by user".into(),
+                Response::error(
+                    ErrorKind::NotFound,
+                    "Port not found or not owned by user",
                 )
             }
         }
+
+        Command::PortFree { port } => {
+            let mut ports = ports.lock().await;
+            match ports.free(port, &creds.user, creds.admin) {
+                Ok(()) => Response::Ok(ResponseData::PortFreed),
+                Err(e) => Response::Error(e),
+            }
+        }
+
+        Command::PortTransfer { port, to } => {
+            if users::get_user_by_name(&to).is_none() {
+                return Response::error(
+                    ErrorKind::NotFound,
+                    format!("No such user {}", to),
+                );
+            }
+            let mut ports = ports.lock().await;
+            match ports.transfer(
+                port,
+                &creds.user,
+                creds.admin,
+                to,
+            ) {
+                Ok(()) => {
+                    Response::Ok(ResponseData::PortOffered)
+                }
+                Err(e) => Response::Error(e),
+            }
+        }
+
+        Command::PortAccept { port } => {
+            let mut ports = ports.lock().await;
+            let limit = quota::for_user(&creds.user).ports;
+            if let Err(e) = quota::check(
+                "ports",
+                ports.count(&creds.user),
+                1,
+                limit,
+            ) {
+                return Response::Error(e);
+            }
+            match ports.accept(port, &creds.user) {
+                Ok(()) => {
+                    Response::Ok(ResponseData::PortTransferred)
+                }
+                Err(e) => Response::Error(e),
+            }
+        }
+
+        Command::Admin(cmd) => match creds.admin {
+            true => admin(cmd, creds, state, monitor).await,
+            false => Response::error(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Admin commands are for root and the {} group",
+                    config::current().admin_group
+                ),
+            ),
+        },
+
+        Command::Quota { user } => {
+            let user =
+                user.unwrap_or_else(|| creds.user.clone());
+            if user != creds.user && !creds.admin {
+                return Response::error(
+                    ErrorKind::PermissionDenied,
+                    "Not authorized to view this user's quota",
+                );
+            }
+            let limits = quota::for_user(&user);
+            let (processes, memory) = {
+                let state = state.lock().await;
+                let monitor = monitor.lock().await;
+                (
+                    state.count(&user),
+                    state.memory_used(&user, &monitor),
+                )
+            };
+            let ports = ports.lock().await.count(&user);
+
+            Response::Ok(ResponseData::Quota(QuotaReport {
+                user,
+                processes: QuotaUsage {
+                    used: processes,
+                    limit: limits.processes,
+                },
+                ports: QuotaUsage {
+                    used: ports,
+                    limit: limits.ports,
+                },
+                memory: QuotaUsage {
+                    used: memory,
+                    limit: limits.memory,
+                },
+            }))
+        }
+    }
+}
+
+/// Record exits and restart processes as their events come in.
+async fn handle_events(
+    mut events: UnboundedReceiver<Event>,
+    state: Arc<Mutex<State>>,
+) {
+    while let Some(event) = events.recv().await {
+        let mut state = state.lock().await;
+        let now = SystemTime::now();
+        match event {
+            Event::Exited { id, pid, exit } => {
+                // Stopped or restarted since
+                let Some(process) = state
+                    .processes
+                    .get_mut(&id)
+                    .filter(|p| p.pid == pid)
+                else {
+                    continue;
+                };
+                tracing::info!(
+                    "Process {} exited with {}",
+                    id,
+                    exit.code()
+                );
+                process.record_exit(exit, now);
+                // Whoever is stopping it decides what comes next
+                let stopping = matches!(
+                    process.phase,
+                    Phase::Stopping { .. }
+                );
+                if let Some(at) = (!stopping)
+                    .then(|| process.restart_at(now))
+                    .flatten()
+                {
+                    reaper::schedule_restart(id, pid, at);
+                }
+                state.save();
+            }
+
+            Event::RestartDue { id, pid } => {
+                let Some(old) =
+                    state.processes.get_mut(&id).filter(|p| {
+                        p.pid == pid
+                            && p.phase == Phase::Starting
+                    })
+                else {
+                    continue;
+                };
+                old.record_restart(now);
+                kill_leftovers(old);
+                let spec = old.spec.clone();
+                let restarts = old.restarts.clone();
+
+                match spawn_process(id, spec).await {
+                    Ok(mut new_process) => {
+                        new_process.restarts = restarts;
+                        state.add_process(new_process);
+                        tracing::info!(
+                            "Restarted process {}",
+                            id
+                        );
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to restart process {}: {}",
+                            id,
+                            e
+                        );
+                        // Try again after the next backoff delay
+                        if let Some(at) = old.restart_at(now) {
+                            reaper::schedule_restart(
+                                id, pid, at,
+                            );
+                        }
+                        state.save();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Stop process `id` in the background, so that a slow shutdown
+/// doesn't keep everyone else waiting on the state lock. With
+/// `restart`, it's started again under the same id once it's down;
+/// otherwise it's removed from the table. A stop already under way
+/// is joined instead, and no longer restarts the process unless
+/// `restart` asks for that too.
+fn stop_later(
+    state: &Arc<Mutex<State>>,
+    locked: &mut State,
+    id: u32,
+    restart: bool,
+) -> Stopped {
+    let process = locked.processes.get_mut(&id).unwrap();
+    if let Phase::Stopping { restart: then } = &mut process.phase
+    {
+        *then &= restart;
+    } else {
+        process.phase = Phase::Stopping { restart };
+    }
+    let process = process.clone();
+    locked.save();
+    if let Some(stopped) = locked.stops.get(&id) {
+        return stopped.clone();
+    }
+
+    let (done, stopped) = watch::channel(None);
+    locked.stops.insert(id, stopped.clone());
+    let state = state.clone();
+    tokio::spawn(async move {
+        let result = stop_process(&process).await;
+        let mut state = state.lock().await;
+        state.stops.remove(&id);
+        let result = finish_stop(&mut state, id, result).await;
+        done.send(Some(result)).ok();
+    });
+    stopped
+}
+
+/// Remove or restart a process whose stop has finished, depending
+/// on what it was stopped for.
+async fn finish_stop(
+    state: &mut State,
+    id: u32,
+    stopped: std::io::Result<()>,
+) -> Result<(), RequestError> {
+    let Some(process) = state.processes.get_mut(&id) else {
+        return Ok(());
+    };
+    let stopped = stopped.map_err(|e| {
+        RequestError::new(
+            ErrorKind::Internal,
+            "Failed to stop process",
+        )
+        .details(e)
+    });
+    match (process.phase, stopped) {
+        (Phase::Stopping { restart: true }, Ok(())) => (),
+        (Phase::Stopping { restart: true }, Err(e)) => {
+            // Still around, so back to whatever it was doing
+            process.phase = match process.exit {
+                Some(_) => Phase::Exited,
+                None => Phase::Running,
+            };
+            state.save();
+            return Err(e);
+        }
+        (_, stopped) => {
+            state.remove_process(id);
+            return stopped;
+        }
+    }
+
+    let spec = process.spec.clone();
+    let restarts = process.restarts.clone();
+    match spawn_process(id, spec).await {
+        Ok(mut process) => {
+            process.restarts = restarts;
+            state.add_process(process);
+            Ok(())
+        }
+        Err(e) => {
+            // Left to its restart policy, as if it had exited on
+            // its own
+            process.phase = Phase::Exited;
+            let now = SystemTime::now();
+            if let Some(at) = process
+                .exit
+                .and_then(|_| process.restart_at(now))
+            {
+                reaper::schedule_restart(id, process.pid, at);
+            }
+            state.save();
+            Err(spawn_failed(
+                "Failed to start process".into(),
+                e,
+            ))
+        }
+    }
+}
+
+/// An error for a process that failed to start, blaming the
+/// request where it was at fault.
+fn spawn_failed(
+    message: String,
+    e: std::io::Error,
+) -> RequestError {
+    let kind = match e.kind() {
+        std::io::ErrorKind::InvalidInput
+        | std::io::ErrorKind::Unsupported => {
+            ErrorKind::InvalidRequest
+        }
+        _ => ErrorKind::SpawnFailed { errno: e.raw_os_error() },
+    };
+    RequestError::new(kind, message).details(e)
+}
+
+/// Reply to a stop request, once the process is gone if `wait`
+/// asks for that.
+async fn stop_response(
+    stopped: Stopped,
+    wait: bool,
+) -> Response {
+    if !wait {
+        return Response::Ok(ResponseData::ProcessStopping);
+    }
+    match stop_outcome(stopped).await {
+        Ok(()) => Response::Ok(ResponseData::ProcessStopped),
+        Err(e) => Response::Error(e),
+    }
+}
+
+async fn stop_outcome(
+    mut stopped: Stopped,
+) -> Result<(), RequestError> {
+    let outcome = stopped
+        .wait_for(Option::is_some)
+        .await
+        .map(|outcome| outcome.clone());
+    match outcome {
+        Ok(Some(outcome)) => outcome,
+        // Only if the daemon is going down
+        _ => Err(RequestError::new(
+            ErrorKind::Internal,
+            "The stop was abandoned",
+        )),
+    }
+}
+
+/// Look up a process of any user.
+fn resolve_target(
+    state: &State,
+    target: &AdminTarget,
+) -> Result<u32, RequestError> {
+    let user = match (&target.process, &target.user) {
+        (ProcessRef::Name(_), None) => {
+            return Err(RequestError::new(
+                ErrorKind::InvalidRequest,
+                "Processes of other users need their user to be \
+                 found by name",
+            ));
+        }
+        (_, user) => user.as_deref(),
+    };
+    state
+        .resolve(user.unwrap_or_default(), &target.process)
+        .filter(|id| {
+            user.is_none_or(|user| {
+                state.processes[id].spec.user == user
+            })
+        })
+        .ok_or_else(|| {
+            RequestError::new(
+                ErrorKind::NotFound,
+                "Process not found",
+            )
+        })
+}
+
+/// Carry out a command of an admin, logging anything that changes
+/// another user's processes.
+async fn admin(
+    cmd: AdminCommand,
+    creds: &Credentials,
+    state: &Arc<Mutex<State>>,
+    monitor: &Mutex<SystemMonitor>,
+) -> Response {
+    let mut locked = state.lock().await;
+    match cmd {
+        AdminCommand::List { user } => {
+            let monitor = monitor.lock().await;
+            let mut list = locked.list_processes(&monitor);
+            if let Some(user) = user {
+                list.retain(|p| p.user == user);
+            }
+            Response::Ok(ResponseData::Status(list))
+        }
+
+        AdminCommand::Inspect(target) => {
+            let id = match resolve_target(&locked, &target) {
+                Ok(id) => id,
+                Err(e) => return Response::Error(e),
+            };
+            let monitor = monitor.lock().await;
+            let details = locked.processes[&id]
+                .to_details(&Snapshot::take(), &monitor);
+            Response::Ok(ResponseData::Details(Box::new(
+                details,
+            )))
+        }
+
+        AdminCommand::Stop { target, wait } => {
+            let id = match resolve_target(&locked, &target) {
+                Ok(id) => id,
+                Err(e) => return Response::Error(e),
+            };
+            tracing::info!(
+                "{} is stopping process {} of {}",
+                creds.user,
+                id,
+                locked.processes[&id].spec.user
+            );
+            let stopped =
+                stop_later(state, &mut locked, id, false);
+            drop(locked);
+            stop_response(stopped, wait).await
+        }
+
+        AdminCommand::Restart(target) => {
+            let id = match resolve_target(&locked, &target) {
+                Ok(id) => id,
+                Err(e) => return Response::Error(e),
+            };
+            let process = &locked.processes[&id];
+            if let Phase::Stopping { .. } = process.phase {
+                return Response::error(
+                    ErrorKind::InvalidRequest,
+                    format!(
+                        "Process {} is already being stopped",
+                        id
+                    ),
+                );
+            }
+            tracing::info!(
+                "{} is restarting process {} of {}",
+                creds.user,
+                id,
+                process.spec.user
+            );
+            let stopped =
+                stop_later(state, &mut locked, id, true);
+            drop(locked);
+            match stop_outcome(stopped).await {
+                Ok(()) => {
+                    Response::Ok(ResponseData::ProcessRestarted)
+                }
+                Err(e) => Response::Error(e),
+            }
+        }
+    }
+}
+
+/// Diff `services` against the user's manifest-managed processes
+/// and start, stop or restart whatever doesn't match. `memory` is
+/// what the user's processes use to begin with. Processes are
+/// stopped without holding the state lock, and the services that
+/// replace them started once they're all down.
+async fn apply(
+    user: &str,
+    services: BTreeMap<String, Service>,
+    env: HashMap<String, String>,
+    memory: u64,
+    state: &Arc<Mutex<State>>,
+    ports: &Mutex<PortState>,
+) -> Result<ApplyReport, RequestError> {
+    let mut report = ApplyReport::default();
+    let quota = quota::for_user(user);
+    let mut locked = state.lock().await;
+
+    // Services that are gone from the manifest
+    let removed: Vec<(u32, String)> = locked
+        .processes
+        .values()
+        .filter(|p| {
+            p.spec.user == user && p.spec.service.is_some()
+        })
+        .filter_map(|p| {
+            let name = p.spec.name.clone()?;
+            (!services.contains_key(&name))
+                .then_some((p.id, name))
+        })
+        .collect();
+
+    // Check before touching anything that the names are free and
+    // the services will fit
+    for name in services.keys() {
+        match locked.find_by_name(user, name) {
+            Some(p) if p.spec.service.is_none() => {
+                return Err(RequestError::new(
+                    ErrorKind::InvalidRequest,
+                    format!(
+                        "{} is already the name of a process not \
+                         started from a manifest",
+                        name
+                    ),
+                ));
+            }
+            Some(_) => (),
+            None => locked.check_name(user, name)?,
+        }
+    }
+    // Exited services come back to life, and removed live ones
+    // stop counting
+    let added = services
+        .keys()
+        .filter(|name| {
+            !locked
+                .find_by_name(user, name)
+                .is_some_and(|p| p.is_live())
+        })
+        .count() as u64;
+    let dropped = removed
+        .iter()
+        .filter(|(id, _)| locked.processes[id].is_live())
+        .count() as u64;
+    quota::check(
+        "processes",
+        locked.count(user) - dropped,
+        added,
+        quota.processes,
+    )?;
+    for service in services.values() {
+        quota::check_memory(
+            memory,
+            &service.limits,
+            quota.memory,
+        )?;
+    }
+
+    let mut stops = Vec::new();
+    for (id, name) in removed {
+        stops.push((
+            name.clone(),
+            stop_later(state, &mut locked, id, false),
+        ));
+        report.stopped.push(name);
+    }
+
+    let mut starts = Vec::new();
+    for (name, service) in services {
+        let mut previous_env = HashMap::new();
+        let existing = locked.find_by_name(user, &name);
+
+        if let Some(id) = existing.map(|p| p.id) {
+            let process = &locked.processes[&id];
+            let running = process.is_running();
+            let changed =
+                process.spec.service.as_ref() != Some(&service);
+
+            if running && !changed {
+                report.unchanged.push(name);
+                continue;
+            }
+
+            previous_env = process.spec.env.clone();
+            // Exited ones too, for whatever they left behind
+            stops.push((
+                name.clone(),
+                stop_later(state, &mut locked, id, false),
+            ));
+
+            if changed {
+                report.restarted.push(name.clone());
+            } else {
+                report.started.push(name.clone());
+            }
+        } else {
+            report.started.push(name.clone());
+        }
+        starts.push((name, service, previous_env));
+    }
+    drop(locked);
+
+    for (name, stopped) in stops {
+        stop_outcome(stopped).await.map_err(|mut e| {
+            e.message = format!("{}: {}", name, e.message);
+            e
+        })?;
+    }
+
+    let mut state = state.lock().await;
+    let mut ports = ports.lock().await;
+    for (name, service, previous_env) in starts {
+        // Taken while we were stopping things
+        state.check_name(user, &name)?;
+
+        let mut service_env = match service.clean_env {
+            true => HashMap::new(),
+            false => env.clone(),
+        };
+        service_env.extend(service.env.clone());
+
+        for spec in &service.ports {
+            // Keep a randomly allocated port across restarts as
+            // long as the user still holds it
+            let reused = previous_env
+                .get(&spec.env)
+                .and_then(|p| p.parse::<u16>().ok())
+                .filter(|&p| {
+                    spec.port.is_none_or(|want| want == p)
+                })
+                .filter(|&p| ports.is_owned_by(p, user));
+
+            let port = match (reused, spec.port) {
+                (Some(port), _) => port,
+                (None, Some(port))
+                    if ports.is_owned_by(port, user) =>
+                {
+                    port
+                }
+                (None, requested) => {
+                    let failed = || {
+                        format!(
+                            "Couldn't allocate a port for {} of {}",
+                            spec.env, name
+                        )
+                    };
+                    quota::check(
+                        "ports",
+                        ports.count(user),
+                        1,
+                        quota.ports,
+                    )
+                    .map_err(|e| {
+                        RequestError::new(e.kind, failed())
+                            .details(e.message)
+                    })?;
+                    ports
+                        .allocate(user.into(), requested)
+                        .ok_or_else(|| {
+                            RequestError::new(
+                                ErrorKind::PortUnavailable,
+                                failed(),
+                            )
+                        })?
+                }
+            };
+            service_env
+                .insert(spec.env.clone(), port.to_string());
+        }
+
+        let spec = ProcessSpec {
+            user: user.into(),
+            name: Some(name.clone()),
+            argv: service.argv.clone(),
+            shell: service.shell,
+            cwd: service.cwd.clone().unwrap_or_default(),
+            env: service_env,
+            clean_env: service.clean_env,
+            restart: service.restart,
+            backoff: service.backoff.clone(),
+            stop: service.stop.clone(),
+            limits: service.limits.clone(),
+            logs: service.logs.clone(),
+            service: Some(service),
+        };
+
+        let id = state.next_id();
+        let process =
+            spawn_process(id, spec).await.map_err(|e| {
+                spawn_failed(
+                    format!("Failed to start {}", name),
+                    e,
+                )
+            })?;
+        state.add_process(process);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::process;
+    use hiisi_common::protocol::{Backoff, RestartPolicy};
+
+    fn service() -> Service {
+        ron::from_str("(argv: [\"true\"])").unwrap()
+    }
+
+    fn running(
+        id: u32,
+        name: &str,
+        service: Option<Service>,
+    ) -> State {
+        let mut process =
+            process(RestartPolicy::Never, Backoff::default());
+        process.id = id;
+        process.spec.name = Some(name.into());
+        process.spec.service = service;
+        let mut state = State::default();
+        state.processes.insert(id, process);
+        state
+    }
+
+    async fn assert_untouched(
+        services: BTreeMap<String, Service>,
+        state: State,
+    ) {
+        let count = state.processes.len();
+        let state = Arc::new(Mutex::new(state));
+        let ports = Mutex::new(PortState::default());
+        let result = apply(
+            "alice",
+            services,
+            HashMap::new(),
+            0,
+            &state,
+            &ports,
+        )
+        .await;
+
+        let err = result.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidRequest);
+        let state = state.lock().await;
+        assert_eq!(state.processes.len(), count);
+        assert!(state.stops.is_empty());
+        assert!(matches!(
+            state.processes[&1].phase,
+            Phase::Running
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_name_stops_nothing() {
+        // "web" is gone from the manifest, but "1" is no valid name
+        let services = BTreeMap::from([("1".into(), service())]);
+        let state = running(1, "web", Some(service()));
+        assert_untouched(services, state).await;
+    }
+
+    #[tokio::test]
+    async fn name_conflict_stops_nothing() {
+        let mut state = running(1, "web", Some(service()));
+        let mut other =
+            process(RestartPolicy::Never, Backoff::default());
+        other.id = 2;
+        other.spec.name = Some("worker".into());
+        state.processes.insert(2, other);
+        // "web" is gone, and "worker" wasn't started from one
+        let services =
+            BTreeMap::from([("worker".into(), service())]);
+        assert_untouched(services, state).await;
     }
 }
@@ -0,0 +1,201 @@
+use std::collections::{HashMap, HashSet};
+
+/// `st` of a listening TCP socket in /proc/net/tcp.
+const TCP_LISTEN: &str = "0A";
+/// `st` of an unconnected UDP socket, which is what a UDP server
+/// sits in.
+const UDP_UNCONN: &str = "07";
+
+/// A socket bound to one of the ports we were asked about.
+pub struct Socket {
+    /// Unknown if the owner exited, or lives in another pid
+    /// namespace, while we were looking.
+    pub pid: Option<u32>,
+}
+
+/// Find listening sockets on `ports`, along with the processes
+/// holding them.
+pub fn scan(ports: &HashSet<u16>) -> HashMap<u16, Socket> {
+    let tables = [
+        ("/proc/net/tcp", TCP_LISTEN),
+        ("/proc/net/tcp6", TCP_LISTEN),
+        ("/proc/net/udp", UDP_UNCONN),
+        ("/proc/net/udp6", UDP_UNCONN),
+    ];
+
+    let mut inodes: HashMap<u64, u16> = HashMap::new();
+    for (table, state) in tables {
+        let Ok(contents) = std::fs::read_to_string(table) else {
+            continue;
+        };
+        inodes.extend(
+            contents
+                .lines()
+                .skip(1)
+                .filter_map(|line| parse_line(line, state))
+                .filter(|(_, port)| ports.contains(port)),
+        );
+    }
+
+    let owners = owners(&inodes);
+    let mut sockets = HashMap::new();
+    for (inode, port) in inodes {
+        let pid = owners.get(&inode).copied();
+        // Prefer an entry that found its owner
+        let socket =
+            sockets.entry(port).or_insert(Socket { pid });
+        socket.pid = socket.pid.or(pid);
+    }
+    sockets
+}
+
+/// The inode and local port of a socket in `state`, from a line
+/// of /proc/net/{tcp,udp}{,6}.
+fn parse_line(line: &str, state: &str) -> Option<(u64, u16)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.get(3) != Some(&state) {
+        return None;
+    }
+    let (_, port) = fields.get(1)?.rsplit_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let inode = fields.get(9)?.parse().ok()?;
+    // Sockets mid-teardown have no inode
+    (inode != 0).then_some((inode, port))
+}
+
+/// Map socket inodes to the pids that have them open, by reading
+/// every process's fd links.
+fn owners(inodes: &HashMap<u64, u16>) -> HashMap<u64, u32> {
+    let mut owners = HashMap::new();
+    if inodes.is_empty() {
+        return owners;
+    }
+
+    let pids = std::fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            entry.file_name().to_str()?.parse::<u32>().ok()
+        });
+    for pid in pids {
+        let Ok(fds) =
+            std::fs::read_dir(format!("/proc/{}/fd", pid))
+        else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = std::fs::read_link(fd.path())
+            else {
+                continue;
+            };
+            let inode = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|t| t.parse().ok());
+            if let Some(inode) =
+                inode.filter(|i| inodes.contains_key(i))
+            {
+                owners.entry(inode).or_insert(pid);
+            }
+        }
+    }
+    owners
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, UdpSocket};
+
+    const TCP: &str = "   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 \
+                       00:00000000 00000000  1000        0 123456 1 \
+                       0000000000000000 100 0 0 10 0";
+    // ::1 port 443, each 32-bit word of the address in host order
+    const TCP6: &str = "   1: 00000000000000000000000001000000:01BB \
+                        00000000000000000000000000000000:0000 0A \
+                        00000000:00000000 00:00000000 00000000     0 \
+                        0 654321 1 0000000000000000 100 0 0 10 0";
+    const UDP: &str = " 7301: 00000000:0035 00000000:0000 07 00000000:00000000 \
+                       00:00000000 00000000   101        0 42 2 \
+                       0000000000000000 0";
+
+    #[test]
+    fn decodes_hex_port_and_inode() {
+        assert_eq!(
+            parse_line(TCP, TCP_LISTEN),
+            Some((123456, 8080))
+        );
+        assert_eq!(parse_line(UDP, UDP_UNCONN), Some((42, 53)));
+    }
+
+    #[test]
+    fn decodes_ipv6_lines() {
+        assert_eq!(
+            parse_line(TCP6, TCP_LISTEN),
+            Some((654321, 443))
+        );
+    }
+
+    #[test]
+    fn skips_other_states() {
+        // Established
+        let established = TCP.replacen(" 0A ", " 01 ", 1);
+        assert_eq!(parse_line(&established, TCP_LISTEN), None);
+        assert_eq!(parse_line(TCP, UDP_UNCONN), None);
+        assert_eq!(parse_line(UDP, TCP_LISTEN), None);
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let lines = [
+            "",
+            "  sl  local_address rem_address   st tx_queue rx_queue",
+            "   0: 0100007F 00000000:0000 0A",
+            "   0: 0100007F:XYZW 00000000:0000 0A 0 0 0 0 0 123",
+            "   0: 0100007F:1F90 00000000:0000 0A 0 0 0 0 0 inode",
+            "   0: 0100007F:1F90 00000000:0000 0A",
+            // Mid-teardown, no inode yet
+            "   0: 0100007F:1F90 00000000:0000 0A 0 0 0 0 0 0",
+        ];
+        for line in lines {
+            assert_eq!(
+                parse_line(line, TCP_LISTEN),
+                None,
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn finds_our_own_listeners() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (tcp_port, udp_port) = (
+            tcp.local_addr().unwrap().port(),
+            udp.local_addr().unwrap().port(),
+        );
+
+        let sockets = scan(&HashSet::from([tcp_port, udp_port]));
+        for port in [tcp_port, udp_port] {
+            let socket =
+                sockets.get(&port).expect("port not found");
+            assert_eq!(socket.pid, Some(std::process::id()));
+        }
+    }
+
+    #[test]
+    fn ignores_unlistened_ports() {
+        // A connected socket isn't listening on its local port
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(
+            listener.local_addr().unwrap(),
+        )
+        .unwrap();
+        let port = stream.local_addr().unwrap().port();
+
+        assert!(scan(&HashSet::from([port])).is_empty());
+    }
+}
@@ -1,54 +1,395 @@
-use hiisi_common::protocol::{ProcessInfo, ProcessStatus};
+use hiisi_common::manifest::{LogSettings, Service};
+use hiisi_common::protocol::{
+    Backoff, ErrorKind, ProcessDetails, ProcessInfo, ProcessRef,
+    ProcessStatus, RequestError, ResourceLimits, RestartPolicy,
+    StopPolicy,
+};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
-use tokio::process::Child;
+use tokio::sync::watch;
 
-pub struct Process {
-    pub id: u32,
+use crate::cgroup;
+use crate::config;
+use crate::monitor::SystemMonitor;
+use crate::persist::{self, PersistError};
+use crate::process::is_alive;
+use crate::quota;
+use crate::tree::Snapshot;
+
+/// Everything needed to (re)start a process.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProcessSpec {
     pub user: String,
-    pub cmd: String,
+    /// Set for manifest services, unique among the user's
+    /// processes.
+    pub name: Option<String>,
+    pub argv: Vec<String>,
+    pub shell: bool,
     pub cwd: PathBuf,
+    pub env: HashMap<String, String>,
+    /// Build a login environment for the user underneath `env`.
+    #[serde(default)]
+    pub clean_env: bool,
+    pub restart: RestartPolicy,
+    pub backoff: Backoff,
+    pub stop: StopPolicy,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    pub logs: LogSettings,
+    /// The manifest entry this process was started from, kept
+    /// to tell whether a later `apply` changed it.
+    pub service: Option<Service>,
+}
+
+impl ProcessSpec {
+    /// The command as a user would type it into a shell.
+    pub fn command_line(&self) -> String {
+        if self.shell {
+            return self.argv.join(" ");
+        }
+
+        self.argv
+            .iter()
+            .map(|arg| {
+                let plain = !arg.is_empty()
+                    && arg.bytes().all(|b| {
+                        b.is_ascii_alphanumeric()
+                            || b"-_./=:,+@%".contains(&b)
+                    });
+                if plain {
+                    arg.clone()
+                } else {
+                    format!("'{}'", arg.replace('\'', r"'\''"))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// How a process ended, as far as we can tell.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Exit {
+    Code(i32),
+    Signal(i32),
+    /// A re-adopted process isn't our child, so its exit status
+    /// is lost to us.
+    Unknown,
+}
+
+impl Exit {
+    /// Exit code the way a shell would report it.
+    pub fn code(self) -> i32 {
+        match self {
+            Exit::Code(code) => code,
+            Exit::Signal(signal) => 128 + signal,
+            Exit::Unknown => -1,
+        }
+    }
+
+    /// Whether `policy` restarts a process that ended this way.
+    /// `signalled` is the last signal sent to it through hiisi.
+    fn restarts_under(
+        self,
+        policy: RestartPolicy,
+        signalled: Option<i32>,
+    ) -> bool {
+        match (policy, self) {
+            (RestartPolicy::Never, _) => false,
+            (RestartPolicy::Always, _) => true,
+            (RestartPolicy::OnFailure, exit) => exit.code() != 0,
+            (
+                RestartPolicy::UnlessStopped,
+                Exit::Signal(signal),
+            ) => signalled != Some(signal),
+            (RestartPolicy::UnlessStopped, _) => true,
+        }
+    }
+}
+
+/// Restart bookkeeping, carried over from one incarnation of a
+/// process to the next.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Restarts {
+    pub count: u32,
+    /// Restarts within the backoff window, oldest first.
+    pub recent: Vec<SystemTime>,
+    pub last_exit_code: Option<i32>,
+    /// When the pending restart is due, once its delay has been
+    /// decided.
+    pub next_at: Option<SystemTime>,
+    pub gave_up: bool,
+}
+
+/// Where a process is in its lifecycle.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    Serialize,
+    Deserialize,
+)]
+pub enum Phase {
+    /// Exited, and waiting out its backoff before a restart.
+    Starting,
+    #[default]
+    Running,
+    /// Being shut down in the background. With `restart`, it's
+    /// started again afterwards rather than removed.
+    Stopping { restart: bool },
+    /// Exited, with no restart coming.
+    Exited,
+}
+
+/// Outcome of a stop, once it's known.
+pub type Stopped =
+    watch::Receiver<Option<Result<(), RequestError>>>;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Process {
+    pub id: u32,
+    pub spec: ProcessSpec,
     pub started_at: SystemTime,
-    pub restart: bool,
-    pub child: Child,
+    pub pid: u32,
+    /// Start time from `/proc/<pid>/stat`, used to tell our
+    /// process apart from an unrelated one that reused its pid.
+    pub start_time: u64,
     pub stdout_path: PathBuf,
     pub stderr_path: PathBuf,
-    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub restarts: Restarts,
+    /// The process's own cgroup, if cgroups are in use.
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+    /// How and when this incarnation ended, once it has.
+    #[serde(default)]
+    pub exit: Option<(Exit, SystemTime)>,
+    #[serde(default)]
+    pub phase: Phase,
+    /// The last signal sent with `hiisi signal`. Dying of it counts
+    /// as being stopped on purpose.
+    #[serde(default)]
+    pub signalled: Option<i32>,
 }
 
 impl Process {
-    pub fn to_info(&mut self) -> ProcessInfo {
-        let status = match self.child.try_wait() {
-            Ok(Some(status)) => ProcessStatus::Exited(
-                status.code().unwrap_or(-1),
+    pub fn is_running(&self) -> bool {
+        self.phase == Phase::Running
+    }
+
+    /// Running, or due to be started again. Only these count
+    /// against the owner's quota.
+    pub fn is_live(&self) -> bool {
+        self.phase != Phase::Exited
+    }
+
+    /// Every live pid in the process's tree, leader included.
+    pub fn tree(&self, snapshot: &Snapshot) -> Vec<u32> {
+        match &self.cgroup {
+            Some(cgroup) => cgroup::procs(cgroup),
+            None => snapshot.members(
+                self.pid,
+                is_alive(self.pid, self.start_time),
             ),
-            Ok(None) => ProcessStatus::Running,
-            Err(e) => ProcessStatus::Failed(e.to_string()),
+        }
+    }
+
+    pub fn status(&self) -> ProcessStatus {
+        match (self.restarts.gave_up, self.phase) {
+            (true, _) => ProcessStatus::GaveUp,
+            (false, Phase::Starting) => ProcessStatus::Starting,
+            (false, Phase::Running) => ProcessStatus::Running,
+            (false, Phase::Stopping { .. }) => {
+                ProcessStatus::Stopping
+            }
+            (false, Phase::Exited) => ProcessStatus::Exited(
+                self.exit.map_or(-1, |(exit, _)| exit.code()),
+            ),
+        }
+    }
+
+    /// Record that the process exited at `now`. A process being
+    /// stopped stays that way until the stop is done.
+    pub fn record_exit(&mut self, exit: Exit, now: SystemTime) {
+        self.exit = Some((exit, now));
+        self.restarts.last_exit_code = Some(exit.code());
+        if self.is_running() {
+            self.phase = Phase::Exited;
+        }
+    }
+
+    /// When an exited process should be started again, if its
+    /// restart policy and backoff say it should, moving it to
+    /// `Starting` or `Exited` accordingly.
+    pub fn restart_at(
+        &mut self,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        let at = self.next_restart(now);
+        self.phase = match at {
+            Some(_) => Phase::Starting,
+            None => Phase::Exited,
         };
+        at
+    }
+
+    /// Gives up on the process if it has restarted too often.
+    fn next_restart(
+        &mut self,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        let (exit, _) = self.exit?;
+        if self.restarts.gave_up
+            || !exit.restarts_under(
+                self.spec.restart,
+                self.signalled,
+            )
+        {
+            return None;
+        }
+
+        let backoff = &self.spec.backoff;
+        self.restarts.recent.retain(|t| {
+            now.duration_since(*t)
+                .is_ok_and(|d| d < backoff.window)
+        });
+        let recent = self.restarts.recent.len() as u32;
+        if recent >= backoff.max_restarts {
+            tracing::warn!(
+                "Process {} restarted {} times within {:?}, giving up",
+                self.id,
+                recent,
+                backoff.window
+            );
+            self.restarts.gave_up = true;
+            return None;
+        }
+
+        let delay = backoff
+            .initial
+            .saturating_mul(1 << recent.min(31))
+            .min(backoff.max);
+        Some(*self.restarts.next_at.get_or_insert(now + delay))
+    }
+
+    /// Record a restart attempt made at `now`, successful or not.
+    pub fn record_restart(&mut self, now: SystemTime) {
+        self.restarts.count += 1;
+        self.restarts.recent.push(now);
+        self.restarts.next_at = None;
+    }
+
+    pub fn to_info(
+        &self,
+        snapshot: &Snapshot,
+        monitor: &SystemMonitor,
+    ) -> ProcessInfo {
+        let pids = self.tree(snapshot);
+        let stats = monitor.tree_stats(&pids);
 
         ProcessInfo {
             id: self.id,
-            user: self.user.clone(),
-            uptime: SystemTime::now()
+            name: self.spec.name.clone(),
+            user: self.spec.user.clone(),
+            pid: self.pid,
+            pids,
+            // How long it ran, once it has exited
+            uptime: self
+                .exit
+                .map_or_else(SystemTime::now, |(_, at)| at)
                 .duration_since(self.started_at)
                 .unwrap_or(Duration::from_secs(0)),
-            cwd: self.cwd.clone(),
-            cmd: self.cmd.clone(),
-            status,
+            cwd: self.spec.cwd.clone(),
+            cmd: self.spec.command_line(),
+            status: self.status(),
+            restart: self.spec.restart,
+            restarts: self.restarts.count,
+            last_exit_code: self.restarts.last_exit_code,
+            cpu_usage: stats.cpu_usage,
+            rss: stats.memory,
+            threads: stats.threads,
+            open_fds: stats.open_fds,
+            limits: self.spec.limits.clone(),
+            usage: self
+                .cgroup
+                .as_deref()
+                .and_then(cgroup::usage),
+        }
+    }
+
+    pub fn to_details(
+        &self,
+        snapshot: &Snapshot,
+        monitor: &SystemMonitor,
+    ) -> ProcessDetails {
+        let mut env: Vec<String> =
+            self.spec.env.keys().cloned().collect();
+        env.sort();
+
+        let stop = config::current().stop.with(&self.spec.stop);
+
+        ProcessDetails {
+            info: self.to_info(snapshot, monitor),
+            argv: self.spec.argv.clone(),
+            shell: self.spec.shell,
+            clean_env: self.spec.clean_env,
+            env,
+            stop: StopPolicy {
+                signal: Some(stop.signal),
+                timeout: Some(stop.timeout),
+                term_timeout: Some(stop.term_timeout),
+            },
+            stdout: self.stdout_path.clone(),
+            stderr: self.stderr_path.clone(),
+            service: self.spec.service.is_some(),
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct State {
     pub processes: HashMap<u32, Process>,
     pub next_id: u32,
+    /// Stops under way, for further stop requests to join.
+    #[serde(skip)]
+    pub stops: HashMap<u32, Stopped>,
 }
 
 impl State {
-    pub fn new() -> Self {
-        Self::default()
+    /// Load the saved process table. Fails rather than start
+    /// empty if it's unreadable, since that would orphan every
+    /// process in it.
+    pub fn load() -> Result<Self, PersistError> {
+        let state: Self =
+            persist::load(&config::current().processes_path())?
+                .unwrap_or_default();
+        for process in state.processes.values() {
+            if is_alive(process.pid, process.start_time) {
+                tracing::info!(
+                    "Re-adopted process {} (pid {})",
+                    process.id,
+                    process.pid
+                );
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self) {
+        if let Err(e) = persist::save(
+            &config::current().processes_path(),
+            self,
+        ) {
+            tracing::error!(
+                "Couldn't save process table: {}",
+                e
+            );
+        }
     }
 
     pub fn next_id(&mut self) -> u32 {
@@ -59,23 +400,397 @@ impl State {
 
     pub fn add_process(&mut self, process: Process) {
         self.processes.insert(process.id, process);
+        self.save();
     }
 
     pub fn remove_process(
         &mut self,
         id: u32,
     ) -> Option<Process> {
-        self.processes.remove(&id)
+        let process = self.processes.remove(&id);
+        self.save();
+        process
+    }
+
+    /// How many live processes `user` has. Exited ones that won't
+    /// restart are only kept around to be looked at.
+    pub fn count(&self, user: &str) -> u64 {
+        self.processes
+            .values()
+            .filter(|p| p.spec.user == user && p.is_live())
+            .count() as u64
+    }
+
+    /// Memory in use by `user`'s processes.
+    pub fn memory_used(
+        &self,
+        user: &str,
+        monitor: &SystemMonitor,
+    ) -> u64 {
+        let snapshot = Snapshot::take();
+        let infos: Vec<_> = self
+            .processes
+            .values()
+            .filter(|p| p.spec.user == user)
+            .map(|p| p.to_info(&snapshot, monitor))
+            .collect();
+        quota::memory_used(&infos)
     }
 
     pub fn get_process(&self, id: u32) -> Option<&Process> {
         self.processes.get(&id)
     }
 
-    pub fn list_processes(&mut self) -> Vec<ProcessInfo> {
+    pub fn find_by_name(
+        &self,
+        user: &str,
+        name: &str,
+    ) -> Option<&Process> {
+        self.processes.values().find(|p| {
+            p.spec.user == user
+                && p.spec.name.as_deref() == Some(name)
+        })
+    }
+
+    /// Look up a process id. Names only resolve among `user`'s
+    /// own processes, ids resolve regardless of owner.
+    pub fn resolve(
+        &self,
+        user: &str,
+        process: &ProcessRef,
+    ) -> Option<u32> {
+        match process {
+            ProcessRef::Id(id) => {
+                self.processes.contains_key(id).then_some(*id)
+            }
+            ProcessRef::Name(name) => {
+                self.find_by_name(user, name).map(|p| p.id)
+            }
+        }
+    }
+
+    /// Check that `name` can be given to a new process of `user`.
+    pub fn check_name(
+        &self,
+        user: &str,
+        name: &str,
+    ) -> Result<(), RequestError> {
+        if !ProcessRef::is_valid_name(name) {
+            return Err(RequestError::new(
+                ErrorKind::InvalidRequest,
+                format!(
+                    "Invalid process name {}: use up to {} letters, \
+                     digits, '-', '_' and '.', and not only digits",
+                    name,
+                    ProcessRef::MAX_NAME_LEN
+                ),
+            ));
+        }
+        match self.find_by_name(user, name) {
+            Some(p) => Err(RequestError::new(
+                ErrorKind::InvalidRequest,
+                format!(
+                    "Name {} is already used by process {}",
+                    name, p.id
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Which process's tree each managed pid belongs to, by name
+    /// where there is one.
+    pub fn pid_owners(&self) -> HashMap<u32, ProcessRef> {
+        let snapshot = Snapshot::take();
+        let mut owners = HashMap::new();
+        for process in self.processes.values() {
+            let owner = match &process.spec.name {
+                Some(name) => ProcessRef::Name(name.clone()),
+                None => ProcessRef::Id(process.id),
+            };
+            for pid in process.tree(&snapshot) {
+                owners.insert(pid, owner.clone());
+            }
+        }
+        owners
+    }
+
+    pub fn list_processes(
+        &self,
+        monitor: &SystemMonitor,
+    ) -> Vec<ProcessInfo> {
+        let snapshot = Snapshot::take();
         self.processes
-            .values_mut()
-            .map(Process::to_info)
+            .values()
+            .map(|p| p.to_info(&snapshot, monitor))
             .collect()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const SIGTERM: i32 = 15;
+    const SIGKILL: i32 = 9;
+
+    pub(crate) fn process(
+        restart: RestartPolicy,
+        backoff: Backoff,
+    ) -> Process {
+        Process {
+            id: 1,
+            spec: ProcessSpec {
+                user: "alice".into(),
+                name: None,
+                argv: vec!["true".into()],
+                shell: false,
+                cwd: "/".into(),
+                env: HashMap::new(),
+                clean_env: false,
+                restart,
+                backoff,
+                stop: StopPolicy::default(),
+                limits: ResourceLimits::default(),
+                logs: LogSettings::default(),
+                service: None,
+            },
+            started_at: SystemTime::UNIX_EPOCH,
+            pid: 1,
+            start_time: 0,
+            stdout_path: PathBuf::new(),
+            stderr_path: PathBuf::new(),
+            restarts: Restarts::default(),
+            cgroup: None,
+            exit: None,
+            phase: Phase::Running,
+            signalled: None,
+        }
+    }
+
+    fn backoff(max_restarts: u32) -> Backoff {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            max_restarts,
+            window: Duration::from_secs(600),
+        }
+    }
+
+    fn command_line(argv: &[&str], shell: bool) -> String {
+        let mut process =
+            process(RestartPolicy::Never, Backoff::default());
+        process.spec.argv =
+            argv.iter().map(|arg| arg.to_string()).collect();
+        process.spec.shell = shell;
+        process.spec.command_line()
+    }
+
+    #[test]
+    fn quotes_command_lines() {
+        assert_eq!(
+            command_line(&["./server", "--port=8080"], false),
+            "./server --port=8080"
+        );
+        assert_eq!(
+            command_line(&["echo", "hi there", ""], false),
+            "echo 'hi there' ''"
+        );
+        assert_eq!(
+            command_line(&["echo", "it's", "$HOME"], false),
+            r"echo 'it'\''s' '$HOME'"
+        );
+        assert_eq!(
+            command_line(&["grep", "a\\nb", "*"], false),
+            r"grep 'a\nb' '*'"
+        );
+        // Already a shell command line
+        assert_eq!(
+            command_line(&["echo $HOME", "|", "cat"], true),
+            "echo $HOME | cat"
+        );
+    }
+
+    #[test]
+    fn counts_only_live_processes() {
+        let mut state = State::default();
+        let phases = [
+            Phase::Starting,
+            Phase::Running,
+            Phase::Stopping { restart: false },
+            Phase::Exited,
+        ];
+        for (id, phase) in (1..).zip(phases) {
+            let mut process = process(
+                RestartPolicy::Never,
+                Backoff::default(),
+            );
+            process.id = id;
+            process.phase = phase;
+            state.processes.insert(id, process);
+        }
+        assert_eq!(state.count("alice"), 3);
+        assert_eq!(state.count("bob"), 0);
+    }
+
+    #[test]
+    fn policies_decide_restarts() {
+        use RestartPolicy::*;
+
+        let exits =
+            [Exit::Code(0), Exit::Code(1), Exit::Unknown];
+        for exit in exits {
+            assert!(!exit.restarts_under(Never, None));
+            assert!(exit.restarts_under(Always, None));
+            assert!(exit.restarts_under(UnlessStopped, None));
+        }
+        assert!(!Exit::Code(0).restarts_under(OnFailure, None));
+        assert!(Exit::Code(1).restarts_under(OnFailure, None));
+        assert!(Exit::Signal(SIGKILL)
+            .restarts_under(OnFailure, None));
+        assert!(Exit::Unknown.restarts_under(OnFailure, None));
+    }
+
+    #[test]
+    fn unless_stopped_restarts_after_outside_signals() {
+        let policy = RestartPolicy::UnlessStopped;
+
+        // Someone else's SIGTERM, or the OOM killer's SIGKILL
+        assert!(
+            Exit::Signal(SIGTERM).restarts_under(policy, None)
+        );
+        assert!(
+            Exit::Signal(SIGKILL).restarts_under(policy, None)
+        );
+        // Signalled through hiisi, but died of something else
+        assert!(Exit::Signal(SIGKILL)
+            .restarts_under(policy, Some(SIGTERM)));
+    }
+
+    #[test]
+    fn unless_stopped_stays_down_after_hiisi_signal() {
+        let policy = RestartPolicy::UnlessStopped;
+
+        assert!(!Exit::Signal(SIGTERM)
+            .restarts_under(policy, Some(SIGTERM)));
+        assert!(!Exit::Signal(SIGKILL)
+            .restarts_under(policy, Some(SIGKILL)));
+        // Always doesn't care
+        assert!(Exit::Signal(SIGTERM).restarts_under(
+            RestartPolicy::Always,
+            Some(SIGTERM)
+        ));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut process =
+            process(RestartPolicy::Always, backoff(100));
+        let start =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+
+        let mut delays = Vec::new();
+        for i in 0..6 {
+            let now = start + Duration::from_secs(i);
+            process.record_exit(Exit::Code(1), now);
+            let at = process.restart_at(now).unwrap();
+            assert_eq!(process.phase, Phase::Starting);
+            delays
+                .push(at.duration_since(now).unwrap().as_secs());
+            process.record_restart(now);
+        }
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(process.restarts.count, 6);
+    }
+
+    #[test]
+    fn backoff_keeps_decided_delay() {
+        let mut process =
+            process(RestartPolicy::Always, backoff(100));
+        let now =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        process.record_exit(Exit::Code(1), now);
+
+        let at = process.restart_at(now).unwrap();
+        // As after a daemon restart, later on
+        let later = now + Duration::from_millis(500);
+        assert_eq!(process.restart_at(later), Some(at));
+    }
+
+    #[test]
+    fn backoff_forgets_restarts_outside_window() {
+        let mut process =
+            process(RestartPolicy::Always, backoff(100));
+        let now =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        for _ in 0..3 {
+            process.record_restart(now);
+        }
+
+        let later = now + Duration::from_secs(600);
+        process.record_exit(Exit::Code(1), later);
+        let at = process.restart_at(later).unwrap();
+        assert_eq!(
+            at.duration_since(later).unwrap().as_secs(),
+            1
+        );
+        assert!(process.restarts.recent.is_empty());
+    }
+
+    #[test]
+    fn gives_up_after_max_restarts() {
+        let mut process =
+            process(RestartPolicy::Always, backoff(3));
+        let now =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        for _ in 0..3 {
+            process.record_restart(now);
+        }
+
+        process.record_exit(Exit::Code(1), now);
+        assert_eq!(process.restart_at(now), None);
+        assert!(process.restarts.gave_up);
+        assert_eq!(process.phase, Phase::Exited);
+        assert!(matches!(
+            process.status(),
+            ProcessStatus::GaveUp
+        ));
+        // And stays given up
+        assert_eq!(process.restart_at(now), None);
+    }
+
+    #[test]
+    fn no_restart_by_policy_leaves_exited() {
+        let mut process =
+            process(RestartPolicy::OnFailure, backoff(3));
+        let now = SystemTime::UNIX_EPOCH;
+        process.record_exit(Exit::Code(0), now);
+
+        assert_eq!(process.restart_at(now), None);
+        assert!(!process.restarts.gave_up);
+        assert!(matches!(
+            process.status(),
+            ProcessStatus::Exited(0)
+        ));
+    }
+
+    #[test]
+    fn exit_while_stopping_stays_stopping() {
+        let mut process =
+            process(RestartPolicy::Always, backoff(3));
+        process.phase = Phase::Stopping { restart: false };
+        process.record_exit(
+            Exit::Signal(SIGTERM),
+            SystemTime::UNIX_EPOCH,
+        );
+
+        assert_eq!(
+            process.phase,
+            Phase::Stopping { restart: false }
+        );
+        assert_eq!(
+            process.restarts.last_exit_code,
+            Some(128 + SIGTERM)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::{HashMap, HashSet};
+
+struct Stat {
+    pid: u32,
+    ppid: u32,
+    session: u32,
+}
+
+/// A one-off view of every process on the system, enough to find
+/// the tree under a managed process.
+pub struct Snapshot {
+    stats: Vec<Stat>,
+}
+
+impl Snapshot {
+    pub fn take() -> Self {
+        let stats = std::fs::read_dir("/proc")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                entry.file_name().to_str()?.parse().ok()
+            })
+            .filter_map(read_stat)
+            .collect();
+
+        Self { stats }
+    }
+
+    /// Live processes belonging to `leader`: its session, which
+    /// survives the leader and catches orphaned grandchildren, and
+    /// its descendants, which catches anything that left the
+    /// session but not the tree. Zombies are left out.
+    pub fn members(&self, leader: u32, alive: bool) -> Vec<u32> {
+        // The kernel doesn't hand out a pid still naming a session,
+        // so a dead leader's pid in use again means its session is
+        // gone, and whatever session has that id now isn't ours
+        if !alive && self.stats.iter().any(|s| s.pid == leader) {
+            return Vec::new();
+        }
+
+        let mut members: HashSet<u32> = self
+            .stats
+            .iter()
+            .filter(|s| s.session == leader)
+            .map(|s| s.pid)
+            .collect();
+
+        // A dead leader's pid may belong to someone else by now
+        if alive {
+            let mut children: HashMap<u32, Vec<u32>> =
+                HashMap::new();
+            for stat in &self.stats {
+                children
+                    .entry(stat.ppid)
+                    .or_default()
+                    .push(stat.pid);
+            }
+
+            let mut seen = HashSet::new();
+            let mut queue = vec![leader];
+            while let Some(pid) = queue.pop() {
+                if seen.insert(pid) {
+                    queue.extend(
+                        children.get(&pid).into_iter().flatten(),
+                    );
+                }
+            }
+
+            let live: HashSet<u32> =
+                self.stats.iter().map(|s| s.pid).collect();
+            members.extend(seen.intersection(&live));
+        }
+
+        let mut members: Vec<u32> =
+            members.into_iter().collect();
+        members.sort_unstable();
+        members
+    }
+}
+
+fn read_stat(pid: u32) -> Option<Stat> {
+    let stat =
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .ok()?;
+    // Skip past the command name, which may contain anything
+    let mut fields =
+        stat[stat.rfind(')')? + 1..].split_whitespace();
+
+    let state = fields.next()?;
+    if state == "Z" {
+        return None;
+    }
+    let ppid = fields.next()?.parse().ok()?;
+    let _pgrp = fields.next()?;
+    let session = fields.next()?.parse().ok()?;
+
+    Some(Stat { pid, ppid, session })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(stats: &[(u32, u32, u32)]) -> Snapshot {
+        let stats = stats
+            .iter()
+            .map(|&(pid, ppid, session)| Stat {
+                pid,
+                ppid,
+                session,
+            })
+            .collect();
+        Snapshot { stats }
+    }
+
+    #[test]
+    fn finds_session_and_descendants() {
+        let snapshot = snapshot(&[
+            (1, 0, 1),
+            (100, 1, 100),
+            // Still in the session
+            (101, 100, 100),
+            // Left the session but not the tree
+            (102, 101, 102),
+            (103, 102, 102),
+            // Orphaned, but still in the session
+            (104, 1, 100),
+            (200, 1, 200),
+        ]);
+
+        assert_eq!(
+            snapshot.members(100, true),
+            vec![100, 101, 102, 103, 104]
+        );
+    }
+
+    #[test]
+    fn dead_leader_keeps_its_session() {
+        let snapshot = snapshot(&[
+            (1, 0, 1),
+            (101, 1, 100),
+            (102, 101, 102),
+        ]);
+
+        // Descendants can't be told apart once the leader is gone
+        assert_eq!(snapshot.members(100, false), vec![101]);
+    }
+
+    #[test]
+    fn reused_leader_pid_is_left_alone() {
+        // Some other process got the pid and made a session of it
+        let snapshot = snapshot(&[
+            (1, 0, 1),
+            (100, 1, 100),
+            (101, 100, 100),
+        ]);
+
+        assert!(snapshot.members(100, false).is_empty());
+    }
+}
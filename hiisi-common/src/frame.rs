@@ -9,6 +9,10 @@ const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024; // 16MB limit
 pub enum FrameError {
     Io(io::Error),
     TooLarge(u32),
+    /// The frame was read whole but isn't what we expected, so the
+    /// stream is still in step for the next one.
+    Decode(serde_json::Error),
+    Encode(serde_json::Error),
 }
 
 impl From<io::Error> for FrameError {
@@ -27,6 +31,12 @@ impl std::fmt::Display for FrameError {
             FrameError::TooLarge(size) => {
                 write!(f, "Frame too large: {} bytes", size)
             }
+            FrameError::Decode(e) => {
+                write!(f, "Couldn't decode frame: {}", e)
+            }
+            FrameError::Encode(e) => {
+                write!(f, "Couldn't encode frame: {}", e)
+            }
         }
     }
 }
@@ -38,6 +48,9 @@ impl std::error::Error for FrameError {
         match self {
             FrameError::Io(e) => Some(e),
             FrameError::TooLarge(_) => None,
+            FrameError::Decode(e) | FrameError::Encode(e) => {
+                Some(e)
+            }
         }
     }
 }
@@ -50,8 +63,9 @@ where
     W: AsyncWrite + Unpin,
     T: serde::Serialize,
 {
-    let data = serde_json::to_vec(value).unwrap();
-    let len = data.len() as u32;
+    let data =
+        serde_json::to_vec(value).map_err(FrameError::Encode)?;
+    let len = u32::try_from(data.len()).unwrap_or(u32::MAX);
 
     if len > MAX_FRAME_SIZE {
         return Err(FrameError::TooLarge(len));
@@ -80,5 +94,132 @@ where
     let mut buf = vec![0u8; len as usize];
     reader.read_exact(&mut buf).await?;
 
-    Ok(serde_json::from_slice(&buf).unwrap())
+    serde_json::from_slice(&buf).map_err(FrameError::Decode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Command, Message, ProcessRef};
+    use proptest::prelude::*;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// `payload` behind a length prefix, the way `write_frame`
+    /// would put it.
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        let mut bytes =
+            (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn signal(user: String, id: u32, signal: String) -> Message {
+        Message {
+            user,
+            cmd: Command::Signal {
+                process: ProcessRef::Id(id),
+                signal,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_refused_unread() {
+        let mut bytes =
+            (MAX_FRAME_SIZE + 1).to_be_bytes().to_vec();
+        bytes.extend_from_slice(b"{}");
+        let result =
+            read_frame::<_, Message>(&mut bytes.as_slice())
+                .await;
+        assert!(matches!(
+            result,
+            Err(FrameError::TooLarge(size)) if size == MAX_FRAME_SIZE + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_an_io_error() {
+        let bytes = framed(b"{\"user\": \"alice\"}");
+        let result = read_frame::<_, Message>(
+            &mut &bytes[..bytes.len() - 1],
+        )
+        .await;
+        assert!(matches!(result, Err(FrameError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn unknown_command_is_a_decode_error() {
+        let bytes =
+            framed(br#"{"cmd": "Reboot", "user": "alice"}"#);
+        let result =
+            read_frame::<_, Message>(&mut bytes.as_slice())
+                .await;
+        assert!(matches!(result, Err(FrameError::Decode(_))));
+    }
+
+    proptest! {
+        #[test]
+        fn messages_survive_a_round_trip(
+            user in ".*",
+            id in any::<u32>(),
+            name in ".*",
+        ) {
+            let sent = signal(user, id, name);
+            let received = block_on(async {
+                let mut bytes = Vec::new();
+                write_frame(&mut bytes, &sent).await.unwrap();
+                read_frame::<_, Message>(&mut bytes.as_slice())
+                    .await
+                    .unwrap()
+            });
+            prop_assert_eq!(
+                format!("{:?}", received),
+                format!("{:?}", sent)
+            );
+        }
+
+        #[test]
+        fn arbitrary_bytes_never_panic(
+            bytes in proptest::collection::vec(any::<u8>(), 0..256),
+        ) {
+            block_on(async {
+                read_frame::<_, Message>(&mut bytes.as_slice())
+                    .await
+                    .ok();
+            });
+        }
+
+        #[test]
+        fn bad_frame_leaves_the_stream_in_step(
+            garbage in proptest::collection::vec(any::<u8>(), 0..256),
+            user in "[a-z]{1,16}",
+        ) {
+            // Anything JSON-like could happen to decode, so make
+            // sure it doesn't
+            let mut garbage = garbage;
+            garbage.insert(0, b'#');
+            let sent = signal(user, 1, "HUP".into());
+
+            let (first, second) = block_on(async {
+                let mut bytes = framed(&garbage);
+                write_frame(&mut bytes, &sent).await.unwrap();
+                let mut reader = bytes.as_slice();
+                (
+                    read_frame::<_, Message>(&mut reader).await,
+                    read_frame::<_, Message>(&mut reader).await,
+                )
+            });
+            prop_assert!(matches!(first, Err(FrameError::Decode(_))));
+            prop_assert_eq!(
+                format!("{:?}", second.unwrap()),
+                format!("{:?}", sent)
+            );
+        }
+    }
 }
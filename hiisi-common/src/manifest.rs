@@ -0,0 +1,136 @@
+use ron::extensions::Extensions;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::protocol::{
+    Backoff, ResourceLimits, RestartPolicy, StopPolicy,
+};
+
+/// A user's declarative description of the services they want
+/// running, usually kept as `hiisi.ron` next to their code.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub services: BTreeMap<String, Service>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Service {
+    pub argv: Vec<String>,
+    /// Run `argv` joined with spaces through `/bin/sh -c`, e.g. to
+    /// expand `$PORT` or use pipes.
+    #[serde(default)]
+    pub shell: bool,
+    /// Relative paths are resolved against the manifest's
+    /// directory, which is also the default.
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Start from a login environment instead of the environment
+    /// `hiisi apply` was run in. `env` still applies on top.
+    #[serde(default)]
+    pub clean_env: bool,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub backoff: Backoff,
+    #[serde(default)]
+    pub stop: StopPolicy,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    #[serde(default)]
+    pub ports: Vec<PortSpec>,
+    #[serde(default)]
+    pub logs: LogSettings,
+}
+
+/// A port to allocate for a service, handed to it through the
+/// environment variable `env`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortSpec {
+    pub env: String,
+    /// A specific port, or any free one if unset.
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogSettings {
+    /// Directory under `~/.logs`, defaults to one named after
+    /// the working directory.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+    /// Append to existing log files instead of truncating them
+    /// on every start.
+    #[serde(default = "default_append")]
+    pub append: bool,
+}
+
+fn default_append() -> bool {
+    true
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self { dir: None, append: true }
+    }
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(io::Error),
+    Parse(ron::error::SpannedError),
+}
+
+impl Manifest {
+    /// Load a manifest and resolve every service's working
+    /// directory to an absolute path.
+    pub fn load(path: &Path) -> Result<Self, ManifestError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(ManifestError::Io)?;
+        let mut manifest: Self = ron::Options::default()
+            .with_default_extension(Extensions::IMPLICIT_SOME)
+            .from_str(&contents)
+            .map_err(ManifestError::Parse)?;
+
+        let base = std::path::absolute(path)
+            .map_err(ManifestError::Io)?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("/"));
+
+        for service in manifest.services.values_mut() {
+            service.cwd = Some(match service.cwd.take() {
+                Some(cwd) => base.join(cwd),
+                None => base.clone(),
+            });
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Io(e) => write!(f, "IO error: {}", e),
+            ManifestError::Parse(e) => {
+                write!(f, "Invalid manifest: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {
+    fn source(
+        &self,
+    ) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ManifestError::Io(e) => Some(e),
+            ManifestError::Parse(e) => Some(e),
+        }
+    }
+}
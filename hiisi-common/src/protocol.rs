@@ -1,24 +1,378 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
+use crate::manifest::Service;
+
+/// Protocol version spoken by this build. Bumped whenever a change
+/// would trip up a peer that doesn't know about it.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version still spoken. Version 1, from before the
+/// handshake, isn't: its messages and responses have since changed
+/// shape.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// A response in the shape version 1 clients decode, used to tell
+/// them to upgrade before hanging up.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LegacyResponse {
+    Error(String),
+}
+
+/// Optional behaviour peers announce in their handshake.
+pub mod capability {
+    /// The client understands `ResponseData::ProcessStopping`, so
+    /// stops can be answered before the process is gone.
+    pub const BACKGROUND_STOP: &str = "background-stop";
+    /// The daemon gives processes cgroups, so resource limits and
+    /// usage are available.
+    pub const CGROUPS: &str = "cgroups";
+}
+
+/// A process as the user refers to it, by numeric id or by the
+/// name it was given.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ProcessRef {
+    Id(u32),
+    Name(String),
+}
+
+impl ProcessRef {
+    /// Longest name a process can be given, in bytes.
+    pub const MAX_NAME_LEN: usize = 64;
+
+    /// Names are unique per user and can't be all digits, so that
+    /// they never shadow an id.
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= Self::MAX_NAME_LEN
+            && !name.bytes().all(|b| b.is_ascii_digit())
+            && name.bytes().all(|b| {
+                b.is_ascii_alphanumeric() || b"-_.".contains(&b)
+            })
+    }
+}
+
+impl FromStr for ProcessRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(id) => Ok(Self::Id(id)),
+            Err(_) if Self::is_valid_name(s) => {
+                Ok(Self::Name(s.into()))
+            }
+            Err(_) => {
+                Err(format!("Invalid process name: {}", s))
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProcessRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{id}"),
+            Self::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// When a process that exited should be started again.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Whatever the exit status.
+    Always,
+    /// Only after a non-zero exit or a crash.
+    OnFailure,
+    /// Like `Always`, except after dying of a signal sent with
+    /// `hiisi signal`, which is taken as stopping it on purpose.
+    /// Signals from anywhere else are treated as crashes.
+    UnlessStopped,
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Never => write!(f, "never"),
+            Self::Always => write!(f, "always"),
+            Self::OnFailure => write!(f, "on-failure"),
+            Self::UnlessStopped => write!(f, "unless-stopped"),
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "always" => Ok(Self::Always),
+            "on-failure" => Ok(Self::OnFailure),
+            "unless-stopped" => Ok(Self::UnlessStopped),
+            _ => Err(format!(
+                "Unknown restart policy {}, expected never, \
+                 always, on-failure or unless-stopped",
+                s
+            )),
+        }
+    }
+}
+
+/// How quickly restarts are retried and when to give up. The
+/// delay doubles with every restart inside `window`, and after
+/// `max_restarts` of them the process is left stopped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub max_restarts: u32,
+    pub window: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            max_restarts: 10,
+            window: Duration::from_secs(600),
+        }
+    }
+}
+
+/// How `stop` shuts a process down: `signal` first, SIGTERM if
+/// it's still running after `timeout`, and SIGKILL after another
+/// `term_timeout`. Whatever is left unset is taken from the
+/// `[stop]` section of the daemon's config.
+#[derive(
+    Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+#[serde(default)]
+pub struct StopPolicy {
+    /// Signal name, e.g. `SIGQUIT` or just `QUIT`.
+    pub signal: Option<String>,
+    pub timeout: Option<Duration>,
+    pub term_timeout: Option<Duration>,
+}
+
+/// Resource limits, enforced through the process's cgroup. Unset
+/// limits are left at the kernel's defaults.
+#[derive(
+    Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Bytes, either a number or a size like `"512M"`. Past this
+    /// the kernel reclaims and eventually OOM-kills within the
+    /// process's tree only.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub memory_max: Option<u64>,
+    /// Share of CPU time under contention, 1-10000 (default 100).
+    pub cpu_weight: Option<u16>,
+    /// Hard cap in percent of one CPU, e.g. 150 for one and a
+    /// half CPUs.
+    pub cpu_quota: Option<u32>,
+    pub pids_max: Option<u64>,
+    /// Share of disk IO under contention, 1-10000 (default 100).
+    pub io_weight: Option<u16>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let weights =
+            [("CPU", self.cpu_weight), ("IO", self.io_weight)];
+        for (what, weight) in weights {
+            if weight.is_some_and(|w| !(1..=10000).contains(&w))
+            {
+                return Err(format!(
+                    "{} weight must be between 1 and 10000",
+                    what
+                ));
+            }
+        }
+        if self.cpu_quota == Some(0) {
+            return Err("CPU quota must be above 0%".into());
+        }
+        if self.pids_max == Some(0) {
+            return Err("Pids limit must be above 0".into());
+        }
+        Ok(())
+    }
+}
+
+/// Parse a byte size: a plain number, or one followed by K, M, G
+/// or T (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split =
+        s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("Invalid size {}", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Invalid size {}", s))
+}
+
+/// Format a byte count the way `parse_size` reads them, give or
+/// take rounding.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.1}{}", value, UNITS[unit]),
+    }
+}
+
+pub fn deserialize_size<'de, D>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct SizeVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for SizeVisitor {
+        type Value = Option<u64>;
+
+        fn expecting(
+            &self,
+            f: &mut fmt::Formatter,
+        ) -> fmt::Result {
+            write!(
+                f,
+                "a number of bytes or a size like \"512M\""
+            )
+        }
+
+        fn visit_u64<E: serde::de::Error>(
+            self,
+            v: u64,
+        ) -> Result<Self::Value, E> {
+            Ok(Some(v))
+        }
+
+        fn visit_i64<E: serde::de::Error>(
+            self,
+            v: i64,
+        ) -> Result<Self::Value, E> {
+            u64::try_from(v).map(Some).map_err(E::custom)
+        }
+
+        fn visit_str<E: serde::de::Error>(
+            self,
+            v: &str,
+        ) -> Result<Self::Value, E> {
+            parse_size(v).map(Some).map_err(E::custom)
+        }
+
+        fn visit_none<E: serde::de::Error>(
+            self,
+        ) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: serde::de::Error>(
+            self,
+        ) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: serde::Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+    }
+
+    deserializer.deserialize_any(SizeVisitor)
+}
+
+/// What a process's cgroup has accounted to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// Bytes, page cache included. Unset without the memory
+    /// controller.
+    pub memory: Option<u64>,
+    pub cpu_time: Duration,
+    /// Times the kernel OOM-killed something in the tree for
+    /// going over `memory_max`.
+    pub oom_kills: u64,
+}
+
+// Sent once per request, so the size of `Run` doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
     Run {
-        cmd: String,
+        name: Option<String>,
+        argv: Vec<String>,
+        /// Run `argv` joined with spaces through `/bin/sh -c`
+        /// instead of executing it directly.
+        shell: bool,
         cwd: PathBuf,
         env: HashMap<String, String>,
-        restart: bool,
+        /// Start from a login environment (HOME, USER, LOGNAME,
+        /// SHELL, PATH) with `env` on top, rather than `env` alone.
+        clean_env: bool,
+        restart: RestartPolicy,
+        backoff: Backoff,
+        stop: StopPolicy,
+        limits: ResourceLimits,
     },
+    /// Stop a process in the background. With `wait`, the reply
+    /// only comes once it's gone.
     Stop {
-        id: u32,
+        process: ProcessRef,
+        #[serde(default)]
+        wait: bool,
     },
     Status,
     Logs {
-        id: u32,
+        process: ProcessRef,
+    },
+    /// Send a signal to a process and everything it spawned.
+    Signal {
+        process: ProcessRef,
+        signal: String,
     },
     PortAllocate {
         port: Option<u16>,
@@ -26,30 +380,130 @@ pub enum Command {
     PortFree {
         port: u16,
     },
+    /// Offer one of your ports to another user, who has to accept
+    /// it before it changes hands.
+    PortTransfer {
+        port: u16,
+        to: String,
+    },
+    /// Accept a port offered to you.
+    PortAccept {
+        port: u16,
+    },
     PortLookup {
         user: Option<String>,
     },
+    /// Exempt a port from being reclaimed when idle, or make it
+    /// reclaimable again.
+    PortPin {
+        port: u16,
+        pinned: bool,
+    },
+    /// Bring the user's manifest-managed services in line with
+    /// `services`. `env` is the base environment they start with.
+    Apply {
+        services: BTreeMap<String, Service>,
+        env: HashMap<String, String>,
+    },
+    /// Show a user's quota and how much of it is in use. Only
+    /// admins may ask about someone else.
+    Quota {
+        user: Option<String>,
+    },
+    /// Manage any user's processes. Root and the admin group only.
+    Admin(AdminCommand),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Every user's processes, or only `user`'s.
+    List {
+        user: Option<String>,
+    },
+    Inspect(AdminTarget),
+    Stop {
+        target: AdminTarget,
+        wait: bool,
+    },
+    /// Stop the process and start it again with the same id.
+    Restart(AdminTarget),
+}
+
+/// A process of any user. Names are looked up among `user`'s
+/// processes, so they need one; ids don't.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminTarget {
+    pub user: Option<String>,
+    pub process: ProcessRef,
+}
+
+/// The first frame a client sends, saying which protocol versions
+/// it speaks.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    pub min_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: Vec<String>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// The newest version spoken both by this build and by
+    /// whoever sent the hello, if there is one.
+    pub fn negotiate(&self) -> Option<u32> {
+        let version = self.version.min(PROTOCOL_VERSION);
+        (version >= self.min_version
+            && version >= MIN_PROTOCOL_VERSION)
+            .then_some(version)
+    }
+}
+
+/// The daemon's answer to `Hello`. Without a `version` the two
+/// have none in common, and the daemon hangs up.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloAck {
+    /// What the connection speaks from here on.
+    pub version: Option<u32>,
+    pub daemon_version: u32,
+    pub min_version: u32,
+    pub capabilities: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
     pub cmd: Command,
+    /// The user the client believes it runs as. The daemon
+    /// cross-checks this against the socket's peer credentials
+    /// and never acts on it alone.
     pub user: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ProcessStatus {
     Running,
-    Exited(i32),    // Exit code if we have it
+    Starting, // Waiting out the backoff before a restart
+    Stopping, // Shutting down after a stop request
+    Exited(i32), // Exit code if we have it
     Failed(String), // Error message if process failed to start/crashed
+    GaveUp,         // Restarted too often, no longer restarting
 }
 
 impl fmt::Display for ProcessStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Running => write!(f, "running"),
+            Self::Starting => write!(f, "starting"),
+            Self::Stopping => write!(f, "stopping"),
             Self::Exited(num) => write!(f, "exited({num})"),
             Self::Failed(err) => write!(f, "failed({err})"),
+            Self::GaveUp => write!(f, "gave-up"),
         }
     }
 }
@@ -57,34 +511,270 @@ impl fmt::Display for ProcessStatus {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProcessInfo {
     pub id: u32,
+    pub name: Option<String>,
     pub user: String,
+    pub pid: u32,
+    /// Every live pid in the process's tree, leader included.
+    pub pids: Vec<u32>,
     pub uptime: Duration,
     pub cwd: PathBuf,
+    /// The command line, quoted for display.
     pub cmd: String,
     pub status: ProcessStatus,
+    pub restart: RestartPolicy,
+    pub restarts: u32,
+    pub last_exit_code: Option<i32>,
+    /// Percent of one CPU, summed over the tree.
+    pub cpu_usage: f32,
+    /// Resident memory in bytes, summed over the tree.
+    pub rss: u64,
+    pub threads: u64,
+    pub open_fds: u64,
+    pub limits: ResourceLimits,
+    /// Unset when the process has no cgroup.
+    pub usage: Option<ResourceUsage>,
+}
+
+/// Everything about a process, for admins.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProcessDetails {
+    pub info: ProcessInfo,
+    pub argv: Vec<String>,
+    pub shell: bool,
+    pub clean_env: bool,
+    /// Variable names only, since values may be secrets.
+    pub env: Vec<String>,
+    /// With the daemon's defaults filled in.
+    pub stop: StopPolicy,
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+    /// Started from a manifest by `hiisi apply`.
+    pub service: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PortInfo {
     pub port: u16,
     pub user: String,
+    /// Whether something is listening on the port.
     pub active: bool,
+    /// The listening process, when it could be found.
+    pub pid: Option<u32>,
+    /// The hiisi process `pid` belongs to, if any.
+    pub process: Option<ProcessRef>,
+    /// When something was last seen listening on the port.
+    pub last_seen: Option<DateTime<Utc>>,
+    pub pinned: bool,
+    /// A user the port has been offered to, pending their accept.
+    pub offered_to: Option<String>,
+    /// Set once the port has been idle long enough to be stale:
+    /// when it will be freed unless something listens on it.
+    pub reclaim_at: Option<DateTime<Utc>>,
     pub allocated_at: DateTime<Utc>,
 }
 
+/// Usage of one quota'd resource. No limit means unlimited.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuotaReport {
+    pub user: String,
+    pub processes: QuotaUsage,
+    pub ports: QuotaUsage,
+    /// In bytes.
+    pub memory: QuotaUsage,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ApplyReport {
+    pub started: Vec<String>,
+    pub restarted: Vec<String>,
+    pub stopped: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ResponseData {
-    ProcessStarted { id: u32 },
+    ProcessStarted {
+        id: u32,
+    },
     ProcessStopped,
+    /// The process is being stopped in the background.
+    ProcessStopping,
+    Signalled {
+        pids: usize,
+    },
     Status(Vec<ProcessInfo>),
-    Logs { stdout: PathBuf, stderr: PathBuf },
-    PortAllocated { port: u16 },
+    Logs {
+        stdout: PathBuf,
+        stderr: PathBuf,
+    },
+    PortAllocated {
+        port: u16,
+    },
     PortFreed,
+    PortPinned,
+    /// The port was offered and awaits the recipient's accept.
+    PortOffered,
+    PortTransferred,
     PortList(Vec<PortInfo>),
+    Applied(ApplyReport),
+    Quota(QuotaReport),
+    Details(Box<ProcessDetails>),
+    ProcessRestarted,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Ok(ResponseData),
-    Error(String),
+    Error(RequestError),
+}
+
+impl Response {
+    pub fn error(
+        kind: ErrorKind,
+        message: impl Into<String>,
+    ) -> Self {
+        Self::Error(RequestError::new(kind, message))
+    }
+}
+
+/// What kind of failure a request ran into, for scripts to tell
+/// apart without parsing messages.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ErrorKind {
+    /// No such process, port, offer or user.
+    NotFound,
+    PermissionDenied,
+    /// Taken, out of range or otherwise not to be had.
+    PortUnavailable,
+    QuotaExceeded,
+    /// The process couldn't be started, with the OS error behind
+    /// it if there was one.
+    SpawnFailed {
+        errno: Option<i32>,
+    },
+    /// Malformed or contradictory, and not worth retrying as is.
+    InvalidRequest,
+    /// Something went wrong on the daemon's side.
+    Internal,
+}
+
+/// Why a request failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// The underlying cause, such as an OS error.
+    pub details: Option<String>,
+}
+
+impl RequestError {
+    pub fn new(
+        kind: ErrorKind,
+        message: impl Into<String>,
+    ) -> Self {
+        Self { kind, message: message.into(), details: None }
+    }
+
+    pub fn details(
+        mut self,
+        details: impl fmt::Display,
+    ) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.details {
+            Some(details) => {
+                write!(f, "{}: {}", self.message, details)
+            }
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_process_refs() {
+        assert_eq!("42".parse(), Ok(ProcessRef::Id(42)));
+        assert_eq!(
+            "api-v2.1_x".parse(),
+            Ok(ProcessRef::Name("api-v2.1_x".into()))
+        );
+        assert_eq!(
+            "2fast".parse(),
+            Ok(ProcessRef::Name("2fast".into()))
+        );
+        let longest = "a".repeat(ProcessRef::MAX_NAME_LEN);
+        assert_eq!(
+            longest.parse(),
+            Ok(ProcessRef::Name(longest.clone()))
+        );
+
+        // Too big for an id, and names can't be all digits
+        assert!("99999999999".parse::<ProcessRef>().is_err());
+        for name in ["", "a/b", "../x", "a b", "ä"] {
+            assert!(
+                name.parse::<ProcessRef>().is_err(),
+                "{name}"
+            );
+        }
+        let too_long = "a".repeat(ProcessRef::MAX_NAME_LEN + 1);
+        assert!(too_long.parse::<ProcessRef>().is_err());
+    }
+
+    #[test]
+    fn legacy_errors_keep_their_shape() {
+        let error = LegacyResponse::Error("upgrade".into());
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"Error":"upgrade"}"#
+        );
+    }
+
+    #[test]
+    fn negotiates_newest_common_version() {
+        assert_eq!(
+            Hello::new(Vec::new()).negotiate(),
+            Some(PROTOCOL_VERSION)
+        );
+        let newer = Hello {
+            version: PROTOCOL_VERSION + 3,
+            min_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        };
+        assert_eq!(newer.negotiate(), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn turns_down_protocol_1() {
+        let old = Hello {
+            version: 1,
+            min_version: 1,
+            capabilities: Vec::new(),
+        };
+        assert_eq!(old.negotiate(), None);
+    }
+
+    #[test]
+    fn pre_handshake_request_is_not_a_hello() {
+        // What a client from before the handshake opens with
+        let run = r#"{"cmd":{"Run":{"cmd":["sleep","1"],"cwd":"/tmp","env":{},"restart":false}},"user":"alice"}"#;
+        assert!(serde_json::from_str::<Hello>(run).is_err());
+    }
 }
@@ -1,16 +1,108 @@
-use std::path::PathBuf;
-use hiisi_common::frame::{read_frame, write_frame};
-use hiisi_common::protocol::{Command, Message, Response};
+use hiisi_common::frame::{read_frame, write_frame, FrameError};
+use hiisi_common::manifest::Manifest;
+use hiisi_common::protocol::{
+    capability, AdminCommand, AdminTarget, ApplyReport, Backoff,
+    Command, Hello, HelloAck, Message, ProcessDetails,
+    ProcessInfo, ProcessRef, QuotaReport, ResourceLimits,
+    Response, ResponseData, RestartPolicy, StopPolicy,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use std::path::{Path, PathBuf};
 use tokio::net::UnixStream;
 
+const CONFIG_PATH: &str = "/etc/hiisi/hiidet.toml";
+const SOCKET_PATH: &str = "/run/hiisi/hiisi.sock";
+
+/// The socket the daemon's config says it listens on. Anything
+/// unreadable there is the daemon's problem to report, so fall
+/// back to the default.
+pub fn default_socket() -> PathBuf {
+    std::fs::read_to_string(CONFIG_PATH)
+        .ok()
+        .and_then(|contents| {
+            contents.parse::<toml::Table>().ok()
+        })
+        .and_then(|config| {
+            config.get("socket")?.as_str().map(PathBuf::from)
+        })
+        .unwrap_or_else(|| SOCKET_PATH.into())
+}
+
 pub struct Client {
     stream: UnixStream,
 }
 
+/// The daemon isn't listening where we looked for it.
+#[derive(Debug)]
+pub struct ConnectError {
+    pub(crate) socket: PathBuf,
+    pub(crate) source: std::io::Error,
+}
+
+/// The daemon and this client have no protocol version in common.
+#[derive(Debug)]
+pub struct VersionMismatch {
+    pub(crate) daemon_version: u32,
+    pub(crate) daemon_min_version: u32,
+}
+
+/// How `run` should start and look after a process.
+pub struct RunOptions {
+    pub name: Option<String>,
+    pub shell: bool,
+    /// Don't pass on our environment, let the daemon build a
+    /// login environment instead.
+    pub clean_env: bool,
+    pub restart: RestartPolicy,
+    pub backoff: Backoff,
+    pub stop: StopPolicy,
+    pub limits: ResourceLimits,
+}
+
 impl Client {
-    pub async fn connect() -> std::io::Result<Self> {
-        let stream =
-            UnixStream::connect("/run/hiisi/hiisi.sock").await?;
+    /// Connect and agree on a protocol version with the daemon.
+    pub async fn connect(
+        socket: &Path,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut client = Self::open(socket).await?;
+        let hello =
+            Hello::new(vec![capability::BACKGROUND_STOP.into()]);
+        write_frame(&mut client.stream, &hello).await?;
+
+        match read_frame::<_, HelloAck>(&mut client.stream).await
+        {
+            Ok(HelloAck { version: Some(_), .. }) => Ok(client),
+            Ok(ack) => Err(VersionMismatch {
+                daemon_version: ack.daemon_version,
+                daemon_min_version: ack.min_version,
+            }
+            .into()),
+            Err(FrameError::Io(e))
+                if e.kind()
+                    != std::io::ErrorKind::UnexpectedEof =>
+            {
+                Err(FrameError::Io(e).into())
+            }
+            // A daemon from before the handshake, which turned the
+            // hello down as a malformed request or hung up on it
+            Err(FrameError::Decode(_) | FrameError::Io(_)) => {
+                Err(VersionMismatch {
+                    daemon_version: 1,
+                    daemon_min_version: 1,
+                }
+                .into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn open(socket: &Path) -> Result<Self, ConnectError> {
+        let stream = UnixStream::connect(socket).await.map_err(
+            |source| ConnectError {
+                socket: socket.into(),
+                source,
+            },
+        )?;
         Ok(Self { stream })
     }
 
@@ -34,25 +126,46 @@ impl Client {
 
     pub async fn run(
         &mut self,
-        cmd: String,
-        restart: bool,
+        argv: Vec<String>,
+        options: RunOptions,
     ) -> Result<u32, Box<dyn std::error::Error>> {
         let cwd = std::env::current_dir()?;
-        let env = std::env::vars().collect();
+        let env = match options.clean_env {
+            true => Default::default(),
+            false => std::env::vars().collect(),
+        };
 
-        match self.send_command(Command::Run { cmd, cwd, env, restart }).await? {
+        match self
+            .send_command(Command::Run {
+                name: options.name,
+                argv,
+                shell: options.shell,
+                cwd,
+                env,
+                clean_env: options.clean_env,
+                restart: options.restart,
+                backoff: options.backoff,
+                stop: options.stop,
+                limits: options.limits,
+            })
+            .await?
+        {
            Response::Ok(hiisi_common::protocol::ResponseData::ProcessStarted { id }) => Ok(id),
            Response::Error(e) => Err(e.into()),
            _ => Err("Unexpected response".into()),
        }
     }
 
+    /// Stop a process, returning whether it's gone already or
+    /// still being stopped.
     pub async fn stop(
         &mut self,
-        id: u32,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        match self.send_command(Command::Stop { id }).await? {
-           Response::Ok(hiisi_common::protocol::ResponseData::ProcessStopped) => Ok(()),
+        process: ProcessRef,
+        wait: bool,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match self.send_command(Command::Stop { process, wait }).await? {
+           Response::Ok(hiisi_common::protocol::ResponseData::ProcessStopped) => Ok(true),
+           Response::Ok(hiisi_common::protocol::ResponseData::ProcessStopping) => Ok(false),
            Response::Error(e) => Err(e.into()),
            _ => Err("Unexpected response".into()),
        }
@@ -77,10 +190,13 @@ impl Client {
 
     pub async fn logs(
         &mut self,
-        id: u32,
+        process: ProcessRef,
     ) -> Result<(PathBuf, PathBuf), Box<dyn std::error::Error>>
     {
-        match self.send_command(Command::Logs { id }).await? {
+        match self
+            .send_command(Command::Logs { process })
+            .await?
+        {
             Response::Ok(
                 hiisi_common::protocol::ResponseData::Logs {
                     stdout,
@@ -119,6 +235,124 @@ impl Client {
         }
     }
 
+    pub async fn port_transfer(
+        &mut self,
+        port: u16,
+        to: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self
+            .send_command(Command::PortTransfer { port, to })
+            .await?
+        {
+            Response::Ok(
+                hiisi_common::protocol::ResponseData::PortOffered,
+            ) => Ok(()),
+            Response::Error(e) => Err(e.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    pub async fn port_accept(
+        &mut self,
+        port: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.send_command(Command::PortAccept { port }).await? {
+            Response::Ok(
+                hiisi_common::protocol::ResponseData::PortTransferred,
+            ) => Ok(()),
+            Response::Error(e) => Err(e.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    pub async fn port_pin(
+        &mut self,
+        port: u16,
+        pinned: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self
+            .send_command(Command::PortPin { port, pinned })
+            .await?
+        {
+            Response::Ok(
+                hiisi_common::protocol::ResponseData::PortPinned,
+            ) => Ok(()),
+            Response::Error(e) => Err(e.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    async fn admin(
+        &mut self,
+        cmd: AdminCommand,
+    ) -> Result<ResponseData, Box<dyn std::error::Error>> {
+        match self.send_command(Command::Admin(cmd)).await? {
+            Response::Ok(data) => Ok(data),
+            Response::Error(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn admin_list(
+        &mut self,
+        user: Option<String>,
+    ) -> Result<Vec<ProcessInfo>, Box<dyn std::error::Error>>
+    {
+        match self.admin(AdminCommand::List { user }).await? {
+            ResponseData::Status(info) => Ok(info),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    pub async fn admin_inspect(
+        &mut self,
+        target: AdminTarget,
+    ) -> Result<ProcessDetails, Box<dyn std::error::Error>> {
+        match self.admin(AdminCommand::Inspect(target)).await? {
+            ResponseData::Details(details) => Ok(*details),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    pub async fn admin_stop(
+        &mut self,
+        target: AdminTarget,
+        wait: bool,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        match self
+            .admin(AdminCommand::Stop { target, wait })
+            .await?
+        {
+            ResponseData::ProcessStopped => Ok(true),
+            ResponseData::ProcessStopping => Ok(false),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    pub async fn admin_restart(
+        &mut self,
+        target: AdminTarget,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.admin(AdminCommand::Restart(target)).await? {
+            ResponseData::ProcessRestarted => Ok(()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    pub async fn quota(
+        &mut self,
+        user: Option<String>,
+    ) -> Result<QuotaReport, Box<dyn std::error::Error>> {
+        match self.send_command(Command::Quota { user }).await? {
+            Response::Ok(
+                hiisi_common::protocol::ResponseData::Quota(
+                    report,
+                ),
+            ) => Ok(report),
+            Response::Error(e) => Err(e.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
     pub async fn port_lookup(
         &mut self,
         user: Option<String>,
@@ -139,4 +373,94 @@ impl Client {
             _ => Err("Unexpected response".into()),
         }
     }
+
+    pub async fn apply(
+        &mut self,
+        manifest: Manifest,
+    ) -> Result<ApplyReport, Box<dyn std::error::Error>> {
+        let env = std::env::vars().collect();
+
+        match self
+            .send_command(Command::Apply {
+                services: manifest.services,
+                env,
+            })
+            .await?
+        {
+            Response::Ok(
+                hiisi_common::protocol::ResponseData::Applied(
+                    report,
+                ),
+            ) => Ok(report),
+            Response::Error(e) => Err(e.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+
+    pub async fn signal(
+        &mut self,
+        process: ProcessRef,
+        signal: String,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        match self
+            .send_command(Command::Signal { process, signal })
+            .await?
+        {
+            Response::Ok(
+                hiisi_common::protocol::ResponseData::Signalled {
+                    pids,
+                },
+            ) => Ok(pids),
+            Response::Error(e) => Err(e.into()),
+            _ => Err("Unexpected response".into()),
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(
+            f,
+            "Couldn't connect to hiidet at {}: {}",
+            self.socket.display(),
+            self.source
+        )
+    }
 }
+
+impl std::error::Error for ConnectError {
+    fn source(
+        &self,
+    ) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        if self.daemon_version < MIN_PROTOCOL_VERSION {
+            write!(
+                f,
+                "hiidet is too old for this hiisi: it speaks protocol \
+                 {}, and this hiisi needs {} or newer. Upgrade hiidet",
+                self.daemon_version, MIN_PROTOCOL_VERSION
+            )
+        } else {
+            write!(
+                f,
+                "hiidet is too new for this hiisi: it needs protocol \
+                 {} or newer, and this hiisi speaks up to {}. Upgrade \
+                 hiisi",
+                self.daemon_min_version, PROTOCOL_VERSION
+            )
+        }
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
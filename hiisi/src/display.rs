@@ -1,4 +1,8 @@
-use hiisi_common::protocol::{PortInfo, ProcessInfo};
+use chrono::{DateTime, Utc};
+use hiisi_common::protocol::{
+    format_size, ApplyReport, PortInfo, ProcessDetails,
+    ProcessInfo, QuotaReport, QuotaUsage,
+};
 use std::time::Duration;
 use tabled::{settings::Style, Table, Tabled};
 
@@ -6,10 +10,30 @@ use tabled::{settings::Style, Table, Tabled};
 struct ProcessRow {
     #[tabled(rename = "ID")]
     id: u32,
+    #[tabled(rename = "NAME")]
+    name: String,
     #[tabled(rename = "USER")]
     user: String,
     #[tabled(rename = "STATUS")]
     status: String,
+    #[tabled(rename = "PIDS")]
+    pids: String,
+    #[tabled(rename = "RESTARTS")]
+    restarts: String,
+    #[tabled(rename = "LAST EXIT")]
+    last_exit: String,
+    #[tabled(rename = "CPU%")]
+    cpu: String,
+    #[tabled(rename = "RSS")]
+    rss: String,
+    #[tabled(rename = "THREADS")]
+    threads: String,
+    #[tabled(rename = "FDS")]
+    fds: String,
+    #[tabled(rename = "MEMORY")]
+    memory: String,
+    #[tabled(rename = "CPU TIME")]
+    cpu_time: String,
     #[tabled(rename = "UPTIME")]
     uptime: String,
     #[tabled(rename = "CWD")]
@@ -26,10 +50,54 @@ struct PortRow {
     user: String,
     #[tabled(rename = "STATUS")]
     status: String,
+    #[tabled(rename = "PID")]
+    pid: String,
+    #[tabled(rename = "PROCESS")]
+    process: String,
+    #[tabled(rename = "LEASE")]
+    lease: String,
+    #[tabled(rename = "OFFERED TO")]
+    offered_to: String,
     #[tabled(rename = "ALLOCATED")]
     allocated: String,
 }
 
+#[derive(Tabled)]
+struct DetailRow {
+    #[tabled(rename = "FIELD")]
+    field: &'static str,
+    #[tabled(rename = "VALUE")]
+    value: String,
+}
+
+#[derive(Tabled)]
+struct QuotaRow {
+    #[tabled(rename = "RESOURCE")]
+    resource: &'static str,
+    #[tabled(rename = "USED")]
+    used: String,
+    #[tabled(rename = "LIMIT")]
+    limit: String,
+}
+
+/// Memory in use against the limit, flagging OOM kills.
+fn format_memory(p: &ProcessInfo) -> String {
+    let Some(usage) = &p.usage else {
+        return String::new();
+    };
+    let Some(memory) = usage.memory else {
+        return String::new();
+    };
+    let mut memory = format_size(memory);
+    if let Some(max) = p.limits.memory_max {
+        memory = format!("{} / {}", memory, format_size(max));
+    }
+    if usage.oom_kills > 0 {
+        memory = format!("{} ({} OOM)", memory, usage.oom_kills);
+    }
+    memory
+}
+
 fn format_duration(d: Duration) -> String {
     let secs = d.as_secs();
     if secs < 60 {
@@ -46,13 +114,44 @@ fn format_duration(d: Duration) -> String {
 pub fn format_processes(processes: &[ProcessInfo]) -> String {
     let rows: Vec<ProcessRow> = processes
         .iter()
-        .map(|p| ProcessRow {
-            id: p.id,
-            user: p.user.clone(),
-            uptime: format_duration(p.uptime),
-            cwd: p.cwd.to_string_lossy().into_owned(),
-            cmd: p.cmd.clone(),
-            status: p.status.to_string(),
+        .map(|p| {
+            // Leave live figures blank for processes that aren't
+            let live = |value: String| match p.pids.is_empty() {
+                true => String::new(),
+                false => value,
+            };
+            ProcessRow {
+                id: p.id,
+                name: p.name.clone().unwrap_or_default(),
+                user: p.user.clone(),
+                pids: match p.pids.len() {
+                    0 => String::new(),
+                    1 => p.pids[0].to_string(),
+                    n => format!("{} (+{})", p.pid, n - 1),
+                },
+                restarts: format!(
+                    "{} ({})",
+                    p.restarts, p.restart
+                ),
+                last_exit: p
+                    .last_exit_code
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+                cpu: live(format!("{:.1}", p.cpu_usage)),
+                rss: live(format_size(p.rss)),
+                threads: live(p.threads.to_string()),
+                fds: live(p.open_fds.to_string()),
+                memory: format_memory(p),
+                cpu_time: p
+                    .usage
+                    .as_ref()
+                    .map(|u| format_duration(u.cpu_time))
+                    .unwrap_or_default(),
+                uptime: format_duration(p.uptime),
+                cwd: p.cwd.to_string_lossy().into_owned(),
+                cmd: p.cmd.clone(),
+                status: p.status.to_string(),
+            }
         })
         .collect();
 
@@ -67,8 +166,29 @@ pub fn format_ports(ports: &[PortInfo]) -> String {
         .map(|p| PortRow {
             port: p.port,
             user: p.user.clone(),
-            status: if p.active { "ACTIVE" } else { "IDLE" }
-                .into(),
+            status: match (p.active, p.reclaim_at) {
+                (true, _) => "ACTIVE",
+                (false, None) => "IDLE",
+                (false, Some(_)) => "STALE",
+            }
+            .into(),
+            lease: match (p.pinned, p.reclaim_at) {
+                (true, _) => "pinned".into(),
+                (false, Some(at)) => {
+                    format!("reclaimed in {}", until(at))
+                }
+                (false, None) => String::new(),
+            },
+            offered_to: p.offered_to.clone().unwrap_or_default(),
+            pid: p
+                .pid
+                .map(|pid| pid.to_string())
+                .unwrap_or_default(),
+            process: p
+                .process
+                .as_ref()
+                .map(|p| p.to_string())
+                .unwrap_or_default(),
             allocated: humantime::format_rfc3339(
                 p.allocated_at.into(),
             )
@@ -81,6 +201,169 @@ pub fn format_ports(ports: &[PortInfo]) -> String {
     table.to_string()
 }
 
+/// Time left until `at`, for display.
+fn until(at: DateTime<Utc>) -> String {
+    format_duration(
+        (at - Utc::now()).to_std().unwrap_or_default(),
+    )
+}
+
+/// A warning about `user`'s stale ports, if they have any.
+pub fn format_stale_ports(
+    ports: &[PortInfo],
+    user: &str,
+) -> Option<String> {
+    let stale: Vec<String> = ports
+        .iter()
+        .filter(|p| p.user == user && !p.active)
+        .filter_map(|p| {
+            p.reclaim_at.map(|at| {
+                format!("{} (in {})", p.port, until(at))
+            })
+        })
+        .collect();
+
+    (!stale.is_empty()).then(|| {
+        format!(
+            "Warning: nothing has listened on these ports for a \
+             while, and they will be freed unless used or pinned \
+             with `hiisi port pin`: {}",
+            stale.join(", ")
+        )
+    })
+}
+
+/// Join `words` with spaces into lines of about `width`.
+fn wrap(words: &[String], width: usize) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for word in words {
+        match lines.last_mut() {
+            Some(line)
+                if line.len() + 1 + word.len() <= width =>
+            {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.clone()),
+        }
+    }
+    lines.join("\n")
+}
+
+pub fn format_details(details: &ProcessDetails) -> String {
+    let p = &details.info;
+    let yes_no = |b: bool| match b {
+        true => "yes".to_string(),
+        false => "no".to_string(),
+    };
+    let fields = [
+        ("ID", p.id.to_string()),
+        ("NAME", p.name.clone().unwrap_or_default()),
+        ("USER", p.user.clone()),
+        ("STATUS", p.status.to_string()),
+        (
+            "PIDS",
+            p.pids
+                .iter()
+                .map(|pid| pid.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        ("RESTARTS", format!("{} ({})", p.restarts, p.restart)),
+        (
+            "LAST EXIT",
+            p.last_exit_code
+                .map(|c| c.to_string())
+                .unwrap_or_default(),
+        ),
+        ("UPTIME", format_duration(p.uptime)),
+        ("CWD", p.cwd.to_string_lossy().into_owned()),
+        ("COMMAND", p.cmd.clone()),
+        ("SHELL", yes_no(details.shell)),
+        ("CLEAN ENV", yes_no(details.clean_env)),
+        ("ENV", wrap(&details.env, 60)),
+        (
+            "STOP",
+            format!(
+                "{}, {} then SIGTERM, {} then SIGKILL",
+                details.stop.signal.as_deref().unwrap_or("?"),
+                format_duration(
+                    details.stop.timeout.unwrap_or_default()
+                ),
+                format_duration(
+                    details
+                        .stop
+                        .term_timeout
+                        .unwrap_or_default()
+                )
+            ),
+        ),
+        ("MEMORY", format_memory(p)),
+        ("RSS", format_size(p.rss)),
+        (
+            "STDOUT",
+            details.stdout.to_string_lossy().into_owned(),
+        ),
+        (
+            "STDERR",
+            details.stderr.to_string_lossy().into_owned(),
+        ),
+        ("MANIFEST", yes_no(details.service)),
+    ];
+    let rows = fields
+        .into_iter()
+        .map(|(field, value)| DetailRow { field, value });
+
+    let mut table = Table::new(rows);
+    table.with(Style::modern());
+    table.to_string()
+}
+
+pub fn format_quota(report: &QuotaReport) -> String {
+    let row = |resource,
+               usage: &QuotaUsage,
+               format: fn(u64) -> String| {
+        QuotaRow {
+            resource,
+            used: format(usage.used),
+            limit: usage
+                .limit
+                .map_or("unlimited".into(), format),
+        }
+    };
+    let rows = [
+        row("processes", &report.processes, |n| n.to_string()),
+        row("ports", &report.ports, |n| n.to_string()),
+        row("memory", &report.memory, format_size),
+    ];
+
+    let mut table = Table::new(rows);
+    table.with(Style::modern());
+    format!("Quota of {}\n{}", report.user, table)
+}
+
+pub fn format_apply(report: &ApplyReport) -> String {
+    let sections = [
+        ("Started", &report.started),
+        ("Restarted", &report.restarted),
+        ("Stopped", &report.stopped),
+        ("Unchanged", &report.unchanged),
+    ];
+
+    if sections.iter().all(|(_, names)| names.is_empty()) {
+        return "No services in manifest".into();
+    }
+
+    sections
+        .iter()
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(label, names)| {
+            format!("{}: {}", label, names.join(", "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 pub fn format_error(err: &str) -> String {
     format!("Error: {}", err)
 }
@@ -2,13 +2,26 @@ mod client;
 mod display;
 mod logs;
 
-use clap::{Parser, Subcommand};
-use client::Client;
+use clap::{Args, Parser, Subcommand};
+use client::{
+    Client, ConnectError, RunOptions, VersionMismatch,
+};
+use hiisi_common::frame::FrameError;
+use hiisi_common::manifest::Manifest;
+use hiisi_common::protocol::{
+    parse_size, AdminTarget, Backoff, ErrorKind, ProcessRef,
+    RequestError, ResourceLimits, RestartPolicy, StopPolicy,
+};
 use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Daemon socket, defaults to the one in /etc/hiisi/hiidet.toml
+    #[arg(long, global = true, value_name = "PATH")]
+    socket: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -17,30 +30,220 @@ struct Cli {
 enum Commands {
     /// Start a background process
     Run {
-        /// Restart the process if it dies
+        /// Name to refer to the process by instead of its id
         #[arg(long)]
-        restart: bool,
+        name: Option<String>,
+        /// When to restart the process after it exits: never,
+        /// always, on-failure or unless-stopped
+        #[arg(
+            long,
+            value_name = "POLICY",
+            default_value = "never",
+            num_args = 0..=1,
+            default_missing_value = "always"
+        )]
+        restart: RestartPolicy,
+        #[command(flatten)]
+        backoff: BackoffArgs,
+        #[command(flatten)]
+        stop: StopArgs,
+        #[command(flatten)]
+        limits: LimitsArgs,
+        /// Run the command through /bin/sh -c, for pipes,
+        /// redirects and variable expansion
+        #[arg(long)]
+        shell: bool,
+        /// Don't pass on the current environment, start from a
+        /// login environment (HOME, USER, LOGNAME, SHELL, PATH)
+        #[arg(long)]
+        clean_env: bool,
         /// Command to run
         #[arg(required = true, num_args = 1.., last = true)]
         command: Vec<String>,
     },
     /// Stop a background process
     Stop {
-        /// Process ID
-        id: u32,
+        /// Process ID or name
+        process: ProcessRef,
+        /// Wait until it's gone instead of stopping it in the
+        /// background
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Send a signal to a process and everything it spawned
+    Signal {
+        /// Process ID or name
+        process: ProcessRef,
+        /// Signal name, e.g. SIGHUP or HUP
+        signal: String,
     },
     /// Show running processes
     Status,
     /// Show process logs
     Logs {
-        /// Process ID
-        id: u32,
+        /// Process ID or name
+        process: ProcessRef,
+    },
+    /// Start, stop or restart services to match a manifest
+    Apply {
+        /// Manifest file
+        #[arg(default_value = "hiisi.ron")]
+        file: PathBuf,
+    },
+    /// Show your quota and how much of it is in use
+    Quota {
+        /// Show another user's quota (admins only)
+        user: Option<String>,
     },
     /// Port management
     Port {
         #[command(subcommand)]
         cmd: PortCommands,
     },
+    /// Manage any user's processes and ports (root and the admin
+    /// group only)
+    Admin {
+        #[command(subcommand)]
+        cmd: AdminCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum AdminCommands {
+    /// Show every user's processes
+    Ps {
+        /// Only show this user's processes
+        user: Option<String>,
+    },
+    /// Show everything about a process
+    Inspect(TargetArgs),
+    /// Stop a process
+    Stop {
+        #[command(flatten)]
+        target: TargetArgs,
+        /// Wait until it's gone instead of stopping it in the
+        /// background
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Stop a process and start it again
+    Restart(TargetArgs),
+    /// Show every user's ports
+    Ports {
+        /// Only show this user's ports
+        user: Option<String>,
+    },
+    /// Free anyone's port
+    FreePort {
+        /// Port to free
+        port: u16,
+    },
+}
+
+#[derive(Args)]
+struct TargetArgs {
+    /// Process ID, or name together with --user
+    process: ProcessRef,
+    /// The user whose process it is
+    #[arg(long)]
+    user: Option<String>,
+}
+
+impl From<TargetArgs> for AdminTarget {
+    fn from(args: TargetArgs) -> Self {
+        Self { user: args.user, process: args.process }
+    }
+}
+
+#[derive(Args)]
+struct BackoffArgs {
+    /// Delay before the first restart, doubled for each further
+    /// restart within the window
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    backoff: Option<Duration>,
+    /// Longest delay between restarts
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    max_backoff: Option<Duration>,
+    /// Give up after this many restarts within the window
+    #[arg(long, value_name = "COUNT")]
+    max_restarts: Option<u32>,
+    /// Window for counting restarts
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    restart_window: Option<Duration>,
+}
+
+impl From<BackoffArgs> for Backoff {
+    fn from(args: BackoffArgs) -> Self {
+        let default = Backoff::default();
+        Backoff {
+            initial: args.backoff.unwrap_or(default.initial),
+            max: args.max_backoff.unwrap_or(default.max),
+            max_restarts: args
+                .max_restarts
+                .unwrap_or(default.max_restarts),
+            window: args
+                .restart_window
+                .unwrap_or(default.window),
+        }
+    }
+}
+
+#[derive(Args)]
+struct StopArgs {
+    /// Signal to stop the process with, e.g. SIGQUIT [default:
+    /// the daemon's, SIGINT unless configured otherwise]
+    #[arg(long, value_name = "SIGNAL")]
+    stop_signal: Option<String>,
+    /// How long to wait after the stop signal before sending
+    /// SIGTERM [default: the daemon's, 15s unless configured
+    /// otherwise]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    stop_timeout: Option<Duration>,
+    /// How long to wait after SIGTERM before sending SIGKILL
+    /// [default: the daemon's, 15s unless configured otherwise]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    term_timeout: Option<Duration>,
+}
+
+impl From<StopArgs> for StopPolicy {
+    fn from(args: StopArgs) -> Self {
+        StopPolicy {
+            signal: args.stop_signal,
+            timeout: args.stop_timeout,
+            term_timeout: args.term_timeout,
+        }
+    }
+}
+
+#[derive(Args)]
+struct LimitsArgs {
+    /// Memory limit, e.g. 512M or 2G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    memory_max: Option<u64>,
+    /// Share of CPU time under contention, 1-10000 [default: 100]
+    #[arg(long, value_name = "WEIGHT")]
+    cpu_weight: Option<u16>,
+    /// CPU cap in percent of one CPU, e.g. 150 for one and a half
+    #[arg(long, value_name = "PERCENT")]
+    cpu_quota: Option<u32>,
+    /// Maximum number of processes and threads
+    #[arg(long, value_name = "COUNT")]
+    pids_max: Option<u64>,
+    /// Share of disk IO under contention, 1-10000 [default: 100]
+    #[arg(long, value_name = "WEIGHT")]
+    io_weight: Option<u16>,
+}
+
+impl From<LimitsArgs> for ResourceLimits {
+    fn from(args: LimitsArgs) -> Self {
+        ResourceLimits {
+            memory_max: args.memory_max,
+            cpu_weight: args.cpu_weight,
+            cpu_quota: args.cpu_quota,
+            pids_max: args.pids_max,
+            io_weight: args.io_weight,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -55,38 +258,133 @@ enum PortCommands {
         /// Port to free
         port: u16,
     },
+    /// Offer a port to another user
+    Transfer {
+        /// Port to hand over
+        port: u16,
+        /// User to hand it to
+        user: String,
+    },
+    /// Accept a port another user offered you
+    Accept {
+        /// Port to take over
+        port: u16,
+    },
     /// List allocated ports
     Lookup {
         /// Show ports for specific user
         user: Option<String>,
     },
+    /// Keep a port allocated even while nothing listens on it
+    Pin {
+        /// Port to pin
+        port: u16,
+    },
+    /// Let an idle port be reclaimed again
+    Unpin {
+        /// Port to unpin
+        port: u16,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
-    let mut client = Client::connect().await?;
+async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("{}", display::format_error(&e.to_string()));
+        std::process::exit(exit_code(e.as_ref()));
+    }
+}
+
+/// Exit status for a failed command: one per kind of error the
+/// daemon reports, and one for not getting through to it at all,
+/// so that scripts can tell them apart. Anything else is 1, and
+/// clap uses 2 for bad arguments.
+fn exit_code(e: &(dyn Error + 'static)) -> i32 {
+    if let Some(e) = e.downcast_ref::<RequestError>() {
+        return match e.kind {
+            ErrorKind::NotFound => 3,
+            ErrorKind::PermissionDenied => 4,
+            ErrorKind::PortUnavailable => 5,
+            ErrorKind::QuotaExceeded => 6,
+            ErrorKind::SpawnFailed { .. } => 7,
+            ErrorKind::InvalidRequest => 8,
+            ErrorKind::Internal => 9,
+        };
+    }
+    if e.is::<ConnectError>() || e.is::<FrameError>() {
+        return 10;
+    }
+    if e.is::<VersionMismatch>() {
+        return 11;
+    }
+    1
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let socket =
+        cli.socket.unwrap_or_else(client::default_socket);
+    let mut client = Client::connect(&socket).await?;
 
     match cli.command {
-        Commands::Run { restart, command } => {
-            let cmd = command.join(" ");
-            let id = client.run(cmd, restart).await?;
+        Commands::Run {
+            name,
+            restart,
+            backoff,
+            stop,
+            limits,
+            shell,
+            clean_env,
+            command,
+        } => {
+            let id = client
+                .run(
+                    command,
+                    RunOptions {
+                        name: name.clone(),
+                        shell,
+                        clean_env,
+                        restart,
+                        backoff: backoff.into(),
+                        stop: stop.into(),
+                        limits: limits.into(),
+                    },
+                )
+                .await?;
+            let started = match name {
+                Some(name) => format!("{} ({})", id, name),
+                None => id.to_string(),
+            };
             println!(
                 "{}",
                 display::format_success(&format!(
                     "Started process {}",
-                    id
+                    started
+                ))
+            );
+        }
+
+        Commands::Stop { process, wait } => {
+            let stopped =
+                client.stop(process.clone(), wait).await?;
+            println!(
+                "{}",
+                display::format_success(&format!(
+                    "{} process {}",
+                    if stopped { "Stopped" } else { "Stopping" },
+                    process
                 ))
             );
         }
 
-        Commands::Stop { id } => {
-            client.stop(id).await?;
+        Commands::Signal { process, signal } => {
+            let pids = client
+                .signal(process.clone(), signal.clone())
+                .await?;
             println!(
                 "{}",
                 display::format_success(&format!(
-                    "Stopped process {}",
-                    id
+                    "Sent {} to {} pids of process {}",
+                    signal, pids, process
                 ))
             );
         }
@@ -99,11 +397,88 @@ async fn main() -> Result<(), Box<dyn Error>> {
             );
         }
 
-        Commands::Logs { id } => {
-            let (stdout, stderr) = client.logs(id).await?;
+        Commands::Logs { process } => {
+            let (stdout, stderr) = client.logs(process).await?;
             logs::tail_logs_from_end(stdout, stderr).await?;
         }
 
+        Commands::Apply { file } => {
+            let manifest = Manifest::load(&file)?;
+            let report = client.apply(manifest).await?;
+            println!("{}", display::format_apply(&report));
+        }
+
+        Commands::Quota { user } => {
+            let report = client.quota(user).await?;
+            println!("{}", display::format_quota(&report));
+        }
+
+        Commands::Admin { cmd } => match cmd {
+            AdminCommands::Ps { user } => {
+                let processes = client.admin_list(user).await?;
+                println!(
+                    "{}",
+                    display::format_processes(&processes)
+                );
+            }
+
+            AdminCommands::Inspect(target) => {
+                let details =
+                    client.admin_inspect(target.into()).await?;
+                println!(
+                    "{}",
+                    display::format_details(&details)
+                );
+            }
+
+            AdminCommands::Stop { target, wait } => {
+                let process = target.process.clone();
+                let stopped = client
+                    .admin_stop(target.into(), wait)
+                    .await?;
+                println!(
+                    "{}",
+                    display::format_success(&format!(
+                        "{} process {}",
+                        if stopped {
+                            "Stopped"
+                        } else {
+                            "Stopping"
+                        },
+                        process
+                    ))
+                );
+            }
+
+            AdminCommands::Restart(target) => {
+                let process = target.process.clone();
+                client.admin_restart(target.into()).await?;
+                println!(
+                    "{}",
+                    display::format_success(&format!(
+                        "Restarted process {}",
+                        process
+                    ))
+                );
+            }
+
+            AdminCommands::Ports { user } => {
+                let ports = client.port_lookup(user).await?;
+                println!("{}", display::format_ports(&ports));
+            }
+
+            AdminCommands::FreePort { port } => {
+                client.port_free(port).await?;
+                println!(
+                    "{}",
+                    display::format_success(&format!(
+                        "Freed port {}",
+                        port
+                    ))
+                );
+            }
+        },
+
         Commands::Port { cmd } => match cmd {
             PortCommands::Allocate { port } => {
                 let allocated =
@@ -131,9 +506,117 @@ async fn main() -> Result<(), Box<dyn Error>> {
             PortCommands::Lookup { user } => {
                 let ports = client.port_lookup(user).await?;
                 println!("{}", display::format_ports(&ports));
+
+                let me = users::get_current_username()
+                    .and_then(|name| name.into_string().ok());
+                if let Some(warning) = me.and_then(|me| {
+                    display::format_stale_ports(&ports, &me)
+                }) {
+                    eprintln!("{}", warning);
+                }
+            }
+
+            PortCommands::Transfer { port, user } => {
+                client.port_transfer(port, user.clone()).await?;
+                let message = format!(
+                    "Offered port {} to {}, who can take it with \
+                     `hiisi port accept {}`",
+                    port, user, port
+                );
+                println!(
+                    "{}",
+                    display::format_success(&message)
+                );
+            }
+
+            PortCommands::Accept { port } => {
+                client.port_accept(port).await?;
+                println!(
+                    "{}",
+                    display::format_success(&format!(
+                        "Took over port {}",
+                        port
+                    ))
+                );
+            }
+
+            PortCommands::Pin { port } => {
+                client.port_pin(port, true).await?;
+                println!(
+                    "{}",
+                    display::format_success(&format!(
+                        "Pinned port {}",
+                        port
+                    ))
+                );
+            }
+
+            PortCommands::Unpin { port } => {
+                client.port_pin(port, false).await?;
+                println!(
+                    "{}",
+                    display::format_success(&format!(
+                        "Unpinned port {}",
+                        port
+                    ))
+                );
             }
         },
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_are_fixed_and_distinct() {
+        let io = || std::io::Error::other("oops");
+        let request =
+            |kind| Box::new(RequestError::new(kind, "oops"));
+        let errors: [(Box<dyn Error>, i32); 11] = [
+            (Box::new(io()), 1),
+            (request(ErrorKind::NotFound), 3),
+            (request(ErrorKind::PermissionDenied), 4),
+            (request(ErrorKind::PortUnavailable), 5),
+            (request(ErrorKind::QuotaExceeded), 6),
+            (
+                request(ErrorKind::SpawnFailed {
+                    errno: Some(2),
+                }),
+                7,
+            ),
+            (request(ErrorKind::InvalidRequest), 8),
+            (request(ErrorKind::Internal), 9),
+            (
+                Box::new(ConnectError {
+                    socket: "/run/hiisi/hiisi.sock".into(),
+                    source: io(),
+                }),
+                10,
+            ),
+            (
+                Box::new(VersionMismatch {
+                    daemon_version: 1,
+                    daemon_min_version: 1,
+                }),
+                11,
+            ),
+            // Not getting through, same as failing to connect
+            (Box::new(FrameError::Io(io())), 10),
+        ];
+
+        for (e, code) in &errors {
+            assert_eq!(exit_code(e.as_ref()), *code, "{e}");
+        }
+        // Each gets a code of its own, clear of clap's 2
+        let mut codes: Vec<i32> =
+            errors[..10].iter().map(|(_, code)| *code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), 10);
+        assert!(!codes.contains(&2));
+    }
+}